@@ -0,0 +1,32 @@
+// This file is part of www.nand2tetris.org
+// and the book "The Elements of Computing Systems"
+// by Nisan and Schocken, MIT Press.
+// File name: projects/8/FunctionCalls/SimpleFunction/SimpleFunction.tst
+
+// Tests SimpleFunction.asm on the CPU emulator.
+
+load SimpleFunction.asm,
+output-file SimpleFunction.out,
+compare-to SimpleFunction.cmp,
+
+set RAM[0] 317,    // SP
+set RAM[1] 317,    // LCL
+set RAM[2] 310,    // ARG
+set RAM[3] 3000,   // THIS
+set RAM[4] 4000,   // THAT
+set RAM[310] 1234,
+set RAM[311] 37,
+set RAM[312] 1000,
+set RAM[313] 305,
+set RAM[314] 300,
+set RAM[315] 3010,
+set RAM[316] 4010,
+
+repeat 300 {       // enough cycles to complete the execution
+	ticktock;
+}
+
+// Outputs SP, LCL, ARG, THIS, THAT, and the return value.
+output-list RAM[0]%D2.6.2 RAM[1]%D2.6.2 RAM[2]%D2.6.2 
+            RAM[3]%D2.6.2 RAM[4]%D2.6.2 RAM[310]%D2.6.2;
+output;
@@ -0,0 +1,20 @@
+// This file is part of www.nand2tetris.org
+// and the book "The Elements of Computing Systems"
+// by Nisan and Schocken, MIT Press.
+// File name: projects/8/FunctionCalls/StaticsTest/StaticsTest.tst
+
+// Tests StaticTest.asm in the CPU emulator.
+// This assembly file results from translating the staticsTest folder.
+
+load StaticsTest.asm,
+output-file StaticsTest.out,
+compare-to StaticsTest.cmp,
+
+set RAM[0] 256,
+
+repeat 2500 {
+	ticktock;
+}
+
+output-list RAM[0]%D2.6.2 RAM[261]%D2.6.2 RAM[262]%D2.6.2;
+output;
@@ -1,18 +1,14 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
 use crate::utils::StringLike;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Assembly {
     Label(StringLike),
     Comment(StringLike),
     Address(u32),
     VariableSymbol(StringLike),
-    Command {
-        dest: Option<Dest>,
-        comp: Comp,
-        jump: Option<Jump>,
-    },
+    Command { dest: Dest, comp: Comp, jump: Jump },
 }
 
 impl Display for Assembly {
@@ -22,16 +18,7 @@ impl Display for Assembly {
             Assembly::Comment(s) => write!(f, "// {s}"),
             Assembly::Address(a) => write!(f, "@{a}"),
             Assembly::VariableSymbol(a) => write!(f, "@{a}"),
-            Assembly::Command { dest, comp, jump } => {
-                if let Some(dest) = dest {
-                    dest.fmt(f)?;
-                }
-                comp.fmt(f)?;
-                if let Some(jump) = jump {
-                    jump.fmt(f)?;
-                }
-                Ok(())
-            }
+            Assembly::Command { dest, comp, jump } => write!(f, "{dest}{comp}{jump}"),
         }
     }
 }
@@ -92,95 +79,453 @@ impl Assembly {
 
     pub fn assign(dest: Dest, comp: Comp) -> Self {
         Self::Command {
-            dest: Some(dest),
+            dest,
+            comp,
+            jump: Jump::None,
+        }
+    }
+
+    pub fn jump(comp: Comp, jump: Jump) -> Self {
+        Self::Command {
+            dest: Dest::None,
             comp,
-            jump: None,
+            jump,
+        }
+    }
+
+    /// Whether this takes up a word of ROM, unlike labels and comments
+    pub fn is_instruction(&self) -> bool {
+        !matches!(self, Assembly::Label(_) | Assembly::Comment(_))
+    }
+
+    /// What an instruction does in words, e.g. `RAM[A] = D + 1` for `M=D+1`
+    ///
+    /// Labels, comments and computations stored nowhere have no explanation.
+    pub fn explain(&self) -> Option<String> {
+        match self {
+            Assembly::Label(_) | Assembly::Comment(_) => None,
+            Assembly::Address(a) => Some(format!("A = {a}")),
+            Assembly::VariableSymbol(s) => Some(format!("A = address of {s}")),
+            &Assembly::Command { dest, comp, jump } => {
+                let comp = comp.explain();
+                let dest = dest
+                    .to_string()
+                    .trim_end_matches('=')
+                    .chars()
+                    .map(|c| {
+                        if c == 'M' {
+                            "RAM[A]".to_string()
+                        } else {
+                            c.to_string()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let assign = (!dest.is_empty()).then(|| format!("{dest} = {comp}"));
+                let operand = if assign.is_some() {
+                    "the result"
+                } else {
+                    &comp
+                };
+                let goto = match jump {
+                    Jump::None => None,
+                    Jump::JMP => Some("goto A".to_string()),
+                    Jump::JGT => Some(format!("goto A if {operand} > 0")),
+                    Jump::JEQ => Some(format!("goto A if {operand} == 0")),
+                    Jump::JGE => Some(format!("goto A if {operand} >= 0")),
+                    Jump::JLT => Some(format!("goto A if {operand} < 0")),
+                    Jump::JNE => Some(format!("goto A if {operand} != 0")),
+                    Jump::JLE => Some(format!("goto A if {operand} <= 0")),
+                };
+                match (assign, goto) {
+                    (Some(assign), Some(goto)) => Some(format!("{assign}, {goto}")),
+                    (assign, goto) => assign.or(goto),
+                }
+            }
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Destination of a computation, `None` only stores nothing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Dest {
+    None,
     M,
     D,
-    A,
+    /// Written `MD`
     DM,
+    A,
     AM,
     AD,
+    /// Written `AMD`
     ADM,
 }
 
 impl Display for Dest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self:?}=")
+        match self {
+            Dest::None => Ok(()),
+            Dest::DM => write!(f, "MD="),
+            Dest::ADM => write!(f, "AMD="),
+            _ => write!(f, "{self:?}="),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+/// All 28 computations of the Hack ALU
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Comp {
+    /// 0
     Zero,
+    /// 1
+    One,
+    /// -1
+    MinusOne,
+    /// D
+    D,
     /// A
     A,
     /// M
     M,
-    /// D
-    D,
+    /// !D
+    NotD,
+    /// !A
+    NotA,
+    /// !M
+    NotM,
+    /// -D
+    NegateD,
+    /// -A
+    NegateA,
+    /// -M
+    NegateM,
+    /// D + 1
+    Dplus1,
+    /// A + 1
+    Aplus1,
     /// M + 1
     Mplus1,
-    /// D + M
-    DplusM,
+    /// D - 1
+    Dminus1,
+    /// A - 1
+    Aminus1,
+    /// M - 1
+    Mminus1,
     /// D + A
     DplusA,
+    /// D + M
+    DplusM,
+    /// D - A
+    DminusA,
     /// D - M
     DminusM,
+    /// A - D
+    AminusD,
     /// M - D
     MminusD,
-    /// D - 1
-    Dminus1,
-    /// M - 1
-    Mminus1,
-    /// -M
-    NegateM,
-    /// !M
-    NotM,
+    /// D&A
+    DandA,
     /// D&M
     DandM,
+    /// D|A
+    DorA,
     /// D|M
     DorM,
 }
 
+impl Comp {
+    pub const ALL: [Comp; 28] = [
+        Comp::Zero,
+        Comp::One,
+        Comp::MinusOne,
+        Comp::D,
+        Comp::A,
+        Comp::M,
+        Comp::NotD,
+        Comp::NotA,
+        Comp::NotM,
+        Comp::NegateD,
+        Comp::NegateA,
+        Comp::NegateM,
+        Comp::Dplus1,
+        Comp::Aplus1,
+        Comp::Mplus1,
+        Comp::Dminus1,
+        Comp::Aminus1,
+        Comp::Mminus1,
+        Comp::DplusA,
+        Comp::DplusM,
+        Comp::DminusA,
+        Comp::DminusM,
+        Comp::AminusD,
+        Comp::MminusD,
+        Comp::DandA,
+        Comp::DandM,
+        Comp::DorA,
+        Comp::DorM,
+    ];
+
+    /// `D+M` as `D + RAM[A]`
+    fn explain(self) -> String {
+        let mut out = String::new();
+        for (i, c) in self.to_string().chars().enumerate() {
+            match c {
+                'M' => out.push_str("RAM[A]"),
+                '+' | '-' | '&' | '|' if i > 0 => {
+                    out.push(' ');
+                    out.push(c);
+                    out.push(' ');
+                }
+                c => out.push(c),
+            }
+        }
+        out
+    }
+}
+
 impl Display for Comp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Comp::Zero => write!(f, "0"),
+            Comp::One => write!(f, "1"),
+            Comp::MinusOne => write!(f, "-1"),
+            Comp::NotD => write!(f, "!D"),
+            Comp::NotA => write!(f, "!A"),
+            Comp::NotM => write!(f, "!M"),
+            Comp::NegateD => write!(f, "-D"),
+            Comp::NegateA => write!(f, "-A"),
+            Comp::NegateM => write!(f, "-M"),
+            Comp::Dplus1 => write!(f, "D+1"),
+            Comp::Aplus1 => write!(f, "A+1"),
             Comp::Mplus1 => write!(f, "M+1"),
-            Comp::DplusM => write!(f, "D+M"),
+            Comp::Dminus1 => write!(f, "D-1"),
+            Comp::Aminus1 => write!(f, "A-1"),
+            Comp::Mminus1 => write!(f, "M-1"),
             Comp::DplusA => write!(f, "D+A"),
+            Comp::DplusM => write!(f, "D+M"),
+            Comp::DminusA => write!(f, "D-A"),
             Comp::DminusM => write!(f, "D-M"),
+            Comp::AminusD => write!(f, "A-D"),
             Comp::MminusD => write!(f, "M-D"),
-            Comp::Mminus1 => write!(f, "M-1"),
-            Comp::Dminus1 => write!(f, "D-1"),
-            Comp::NotM => write!(f, "!M"),
-            Comp::NegateM => write!(f, "-M"),
+            Comp::DandA => write!(f, "D&A"),
             Comp::DandM => write!(f, "D&M"),
+            Comp::DorA => write!(f, "D|A"),
             Comp::DorM => write!(f, "D|M"),
-            _ => write!(f, "{self:?}"),
+            Comp::D | Comp::A | Comp::M => write!(f, "{self:?}"),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Jump condition, `None` never jumps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Jump {
-    JLE,
-    JEQ,
+    None,
     JGT,
+    JEQ,
+    JGE,
     JLT,
+    JNE,
+    JLE,
     JMP,
 }
 
 impl Display for Jump {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, ";{self:?}")
+        match self {
+            Jump::None => Ok(()),
+            _ => write!(f, ";{self:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum AsmParseError {
+    #[error("not a valid computation: {0}")]
+    InvalidComp(String),
+    #[error("not a valid destination: {0}")]
+    InvalidDest(String),
+    #[error("not a valid jump: {0}")]
+    InvalidJump(String),
+    #[error("not a valid symbol: {0}")]
+    InvalidSymbol(String),
+    #[error("address does not fit in 15 bits: {0}")]
+    AddressOutOfRange(String),
+}
+
+/// An [`AsmParseError`] with the 1-based line it occurred on
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("line {line}: {error}")]
+pub struct AsmSourceError {
+    pub line: usize,
+    pub error: AsmParseError,
+}
+
+/// Symbols may contain letters, digits, `_`, `.`, `$`, `:` and must not start with a digit
+fn is_symbol(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | ':'))
+}
+
+impl FromStr for Assembly {
+    type Err = AsmParseError;
+
+    /// Parse a single line, which must not have a trailing comment
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(comment) = s.strip_prefix("//") {
+            let comment = comment.strip_prefix(' ').unwrap_or(comment);
+            return Ok(Assembly::comment(comment.to_string()));
+        }
+        if let Some(label) = s.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            return if is_symbol(label) {
+                Ok(Assembly::label(label.to_string()))
+            } else {
+                Err(AsmParseError::InvalidSymbol(label.to_string()))
+            };
+        }
+        if let Some(addr) = s.strip_prefix('@') {
+            if addr.starts_with(|c: char| c.is_ascii_digit()) {
+                return match addr.parse::<u32>() {
+                    Ok(a) if a <= 0x7FFF => Ok(Assembly::Address(a)),
+                    Ok(_) => Err(AsmParseError::AddressOutOfRange(addr.to_string())),
+                    Err(_) => Err(AsmParseError::InvalidSymbol(addr.to_string())),
+                };
+            }
+            return if is_symbol(addr) {
+                Ok(Assembly::addr_sym(addr.to_string()))
+            } else {
+                Err(AsmParseError::InvalidSymbol(addr.to_string()))
+            };
+        }
+
+        // dest=comp;jump, where dest and jump are optional
+        let (dest, rest) = match s.split_once('=') {
+            Some((dest, rest)) => (dest.parse::<Dest>()?, rest),
+            None => (Dest::None, s),
+        };
+        let (comp, jump) = match rest.split_once(';') {
+            Some((comp, jump)) => (comp.parse::<Comp>()?, jump.parse::<Jump>()?),
+            None => (rest.parse::<Comp>()?, Jump::None),
+        };
+        Ok(Assembly::Command { dest, comp, jump })
+    }
+}
+
+/// Parse Hack assembly text, skipping blank lines and trailing comments
+///
+/// Full-line comments are kept as [`Assembly::Comment`]
+pub fn parse_asm(source: &str) -> Result<Vec<Assembly>, AsmSourceError> {
+    let mut program = Vec::new();
+    for (idx, line) in source.lines().enumerate() {
+        let line = line.trim();
+        let code = if line.starts_with("//") {
+            line
+        } else {
+            line.split("//").next().unwrap_or_default().trim()
+        };
+        if code.is_empty() {
+            continue;
+        }
+        let asm = code.parse::<Assembly>().map_err(|error| AsmSourceError {
+            line: idx + 1,
+            error,
+        })?;
+        program.push(asm);
+    }
+    Ok(program)
+}
+
+impl FromStr for Dest {
+    type Err = AsmParseError;
+
+    /// Accepts the registers in any order, e.g. `MD` or `DM`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || AsmParseError::InvalidDest(s.to_string());
+        let (mut a, mut d, mut m) = (false, false, false);
+        for c in s.trim().chars() {
+            let seen = match c {
+                'A' => &mut a,
+                'D' => &mut d,
+                'M' => &mut m,
+                _ => return Err(err()),
+            };
+            if *seen {
+                return Err(err());
+            }
+            *seen = true;
+        }
+        match (a, d, m) {
+            (false, false, false) => Err(err()),
+            (false, false, true) => Ok(Dest::M),
+            (false, true, false) => Ok(Dest::D),
+            (false, true, true) => Ok(Dest::DM),
+            (true, false, false) => Ok(Dest::A),
+            (true, false, true) => Ok(Dest::AM),
+            (true, true, false) => Ok(Dest::AD),
+            (true, true, true) => Ok(Dest::ADM),
+        }
+    }
+}
+
+impl FromStr for Comp {
+    type Err = AsmParseError;
+
+    /// Accepts both operand orders of commutative operations, e.g. `M+D`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let compact = s.split_whitespace().collect::<String>();
+        let comp = match compact.as_str() {
+            "0" => Comp::Zero,
+            "1" => Comp::One,
+            "-1" => Comp::MinusOne,
+            "D" => Comp::D,
+            "A" => Comp::A,
+            "M" => Comp::M,
+            "!D" => Comp::NotD,
+            "!A" => Comp::NotA,
+            "!M" => Comp::NotM,
+            "-D" => Comp::NegateD,
+            "-A" => Comp::NegateA,
+            "-M" => Comp::NegateM,
+            "D+1" | "1+D" => Comp::Dplus1,
+            "A+1" | "1+A" => Comp::Aplus1,
+            "M+1" | "1+M" => Comp::Mplus1,
+            "D-1" => Comp::Dminus1,
+            "A-1" => Comp::Aminus1,
+            "M-1" => Comp::Mminus1,
+            "D+A" | "A+D" => Comp::DplusA,
+            "D+M" | "M+D" => Comp::DplusM,
+            "D-A" => Comp::DminusA,
+            "D-M" => Comp::DminusM,
+            "A-D" => Comp::AminusD,
+            "M-D" => Comp::MminusD,
+            "D&A" | "A&D" => Comp::DandA,
+            "D&M" | "M&D" => Comp::DandM,
+            "D|A" | "A|D" => Comp::DorA,
+            "D|M" | "M|D" => Comp::DorM,
+            _ => return Err(AsmParseError::InvalidComp(s.to_string())),
+        };
+        Ok(comp)
+    }
+}
+
+impl FromStr for Jump {
+    type Err = AsmParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "JGT" => Ok(Jump::JGT),
+            "JEQ" => Ok(Jump::JEQ),
+            "JGE" => Ok(Jump::JGE),
+            "JLT" => Ok(Jump::JLT),
+            "JNE" => Ok(Jump::JNE),
+            "JLE" => Ok(Jump::JLE),
+            "JMP" => Ok(Jump::JMP),
+            _ => Err(AsmParseError::InvalidJump(s.to_string())),
+        }
     }
 }
 
@@ -188,10 +533,102 @@ impl Display for Jump {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_explain() {
+        let explain = |s: &str| s.parse::<Assembly>().unwrap().explain();
+        assert_eq!(explain("@SP").as_deref(), Some("A = address of SP"));
+        assert_eq!(explain("AM=M-1").as_deref(), Some("A, RAM[A] = RAM[A] - 1"));
+        assert_eq!(explain("M=-1").as_deref(), Some("RAM[A] = -1"));
+        assert_eq!(explain("D;JNE").as_deref(), Some("goto A if D != 0"));
+        assert_eq!(
+            explain("D=D-1;JGT").as_deref(),
+            Some("D = D - 1, goto A if the result > 0")
+        );
+        assert_eq!(explain("0;JMP").as_deref(), Some("goto A"));
+        assert_eq!(explain("(LOOP)"), None);
+    }
+
     #[test]
     fn test_display() {
         let res = Dest::M;
         let x = format!("{res}");
         assert_eq!(x, "M=");
+        assert_eq!(
+            Assembly::Command {
+                dest: Dest::ADM,
+                comp: Comp::AminusD,
+                jump: Jump::JGE
+            }
+            .to_string(),
+            "AMD=A-D;JGE"
+        );
+        assert_eq!(Assembly::jump(Comp::Zero, Jump::JMP).to_string(), "0;JMP");
+    }
+
+    const DESTS: [Dest; 8] = [
+        Dest::None,
+        Dest::M,
+        Dest::D,
+        Dest::DM,
+        Dest::A,
+        Dest::AM,
+        Dest::AD,
+        Dest::ADM,
+    ];
+
+    const JUMPS: [Jump; 8] = [
+        Jump::None,
+        Jump::JGT,
+        Jump::JEQ,
+        Jump::JGE,
+        Jump::JLT,
+        Jump::JNE,
+        Jump::JLE,
+        Jump::JMP,
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        // Every C-instruction
+        for dest in DESTS {
+            for comp in Comp::ALL {
+                for jump in JUMPS {
+                    let asm = Assembly::Command { dest, comp, jump };
+                    assert_eq!(asm.to_string().parse::<Assembly>(), Ok(asm));
+                }
+            }
+        }
+        for asm in [
+            Assembly::comment("Push(Segment { segment: Constant, index: 7 })"),
+            Assembly::comment(""),
+            Assembly::label("Main.main$ret.1"),
+            Assembly::Address(0),
+            Assembly::Address(32767),
+            Assembly::addr_sym("Foo.3"),
+            Assembly::sp(),
+        ] {
+            assert_eq!(asm.to_string().parse::<Assembly>(), Ok(asm));
+        }
+    }
+
+    #[test]
+    fn test_parse_aliases() {
+        assert_eq!("M+D".parse::<Comp>(), Ok(Comp::DplusM));
+        assert_eq!("A & D".parse::<Comp>(), Ok(Comp::DandA));
+        assert_eq!("DM".parse::<Dest>(), Ok(Dest::DM));
+        assert_eq!("MAD".parse::<Dest>(), Ok(Dest::ADM));
+        assert!("MM".parse::<Dest>().is_err());
+        assert!("M-A".parse::<Comp>().is_err());
+    }
+
+    #[test]
+    fn test_parse_asm() {
+        let program = parse_asm("// header\n\n@2 // two\nD=A\n(LOOP)\n0;JMP\n").unwrap();
+        assert_eq!(program.len(), 5);
+        assert_eq!(program[1], Assembly::Address(2));
+        let err = parse_asm("@2\nD=Q\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.error, AsmParseError::InvalidComp("Q".to_string()));
+        assert!(parse_asm("@32768").is_err());
     }
 }
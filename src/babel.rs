@@ -1,18 +1,40 @@
+use std::collections::BTreeSet;
+
 use crate::{
     assembly::{Assembly, Comp, Dest, Jump},
     commands::{
-        latt::{pop_latt, push_latt},
-        pointer::{pop_pointer, push_pointer},
-        segment::{Segment, SegmentType},
-        statics::{pop_static, push_static},
-        temp::{pop_temp, push_temp},
-        Command,
+        flow::{goto, if_goto, label},
+        function::{call, call_routine, call_shared, function, return_},
+        latt::{load_latt, pop_latt, push_latt, store_latt},
+        pointer::{load_pointer, pop_pointer, push_pointer, store_pointer},
+        segment::{Segment, SegmentError, SegmentType},
+        statics::{load_static, pop_static, push_static, store_static},
+        temp::{load_temp, pop_temp, push_temp, store_temp},
+        validate_command, Command,
     },
 };
 
+/// Label of the loop the program halts in
+///
+/// Labels the translator generates, return addresses included, start with
+/// `$`, which VM identifiers cannot contain. Labels of the program are plain
+/// identifiers at the top level and `Function$label` inside a function, with
+/// the `$` after a non-empty name, so no two kinds can collide.
+pub const END: &str = "$END";
+
 pub struct Babel {
     counter: usize,
     basename: String,
+    function: Option<String>,
+    /// Call shared routines for comparisons, calls and returns instead of
+    /// inlining them
+    compact: bool,
+    /// Routines used so far in compact mode, see [`Babel::routines`]
+    routines: BTreeSet<Routine>,
+    /// Keep the top of the stack in D between commands where possible
+    cache_top: bool,
+    /// The top of the stack is in D rather than at `RAM[SP - 1]`
+    top_in_d: bool,
 }
 
 impl Babel {
@@ -20,12 +42,128 @@ impl Babel {
         Self {
             counter: 0,
             basename: basename.into(),
+            function: None,
+            compact: false,
+            routines: BTreeSet::new(),
+            cache_top: false,
+            top_in_d: false,
+        }
+    }
+
+    /// Leave values in D for the next command instead of pushing them, see
+    /// [`Babel::flush`]
+    pub fn set_cache_top(&mut self, cache_top: bool) {
+        self.cache_top = cache_top;
+    }
+
+    /// Push the top of the stack if it is held in D, needed before the end of
+    /// the program so the stack is complete in RAM
+    pub fn flush(&mut self) -> Translation {
+        let mut translator = Translation::new();
+        self.spill(&mut translator);
+        translator
+    }
+
+    fn spill(&mut self, translator: &mut Translation) {
+        if self.top_in_d {
+            translator.store_dreg_to_sp();
+            translator.increment_sp();
+            self.top_in_d = false;
+        }
+    }
+
+    /// Pop the top of the stack into D unless it is already there
+    fn fill(&mut self, translator: &mut Translation) {
+        if !self.top_in_d {
+            translator.pop_to_dreg();
+        }
+        self.top_in_d = false;
+    }
+
+    /// Translate `eq`/`gt`/`lt`, `call` and `return` into jumps to shared
+    /// routines, trading speed for program size
+    pub fn set_compact(&mut self, compact: bool) {
+        self.compact = compact;
+    }
+
+    /// Start translating a new file, keeping the label counter so generated
+    /// labels stay unique across the whole program
+    pub fn set_basename<S: Into<String>>(&mut self, basename: S) {
+        self.basename = basename.into();
+        self.function = None;
+    }
+
+    /// Bootstrap code that sets SP to 256 and calls `Sys.init`
+    ///
+    /// With `sentinels`, LCL/ARG/THIS/THAT are also set to -1, -2, -3 and -4,
+    /// the values the nand2tetris test scripts use
+    pub fn bootstrap(&mut self, sentinels: bool) -> Translation {
+        let mut translator = Translation::new();
+        translator.push(Assembly::comment("Bootstrap"));
+        translator.with_asm([
+            // SP = 256
+            Assembly::Address(256),
+            Assembly::assign(Dest::D, Comp::A),
+            Assembly::sp(),
+            Assembly::assign(Dest::M, Comp::D),
+        ]);
+        if sentinels {
+            for (value, base) in [
+                (1, Assembly::local()),
+                (2, Assembly::argument()),
+                (3, Assembly::this()),
+                (4, Assembly::that()),
+            ] {
+                translator.with_asm([
+                    Assembly::Address(value),
+                    Assembly::assign(Dest::D, Comp::NegateA),
+                    base,
+                    Assembly::assign(Dest::M, Comp::D),
+                ]);
+            }
         }
+        // Return address is scoped as `$ret.Bootstrap.N`
+        let basename = std::mem::replace(&mut self.basename, "Bootstrap".to_string());
+        translator.with_asm(self.translate_valid(&Command::Call("Sys.init".to_string(), 0)));
+        self.basename = basename;
+        translator
     }
 
-    pub fn translate(&mut self, cmd: &Command) -> Translation {
+    /// Unique return address label for a call site, e.g. `$ret.Main.main.3`
+    fn return_label(&mut self) -> String {
+        self.counter += 1;
+        let scope = self.function.as_deref().unwrap_or(&self.basename);
+        format!("$ret.{scope}.{}", self.counter)
+    }
+
+    /// Labels are scoped to the enclosing function as `Function$label`
+    fn scoped_label(&self, label: &str) -> String {
+        match &self.function {
+            Some(function) => format!("{function}${label}"),
+            None => label.to_string(),
+        }
+    }
+
+    /// Translate one command, rejecting any that [`validate_command`] does
+    pub fn translate(&mut self, cmd: &Command) -> Result<Translation, SegmentError> {
+        validate_command(cmd)?;
+        Ok(self.translate_valid(cmd))
+    }
+
+    fn translate_valid(&mut self, cmd: &Command) -> Translation {
         let mut translator = Translation::new();
+        // A value left in D belongs to the command that computed it, so push
+        // it before this command's comment unless this command consumes it
+        if !self.takes_top_from_d(cmd) {
+            self.spill(&mut translator);
+        }
         translator.comment(cmd);
+        if self.cache_top && self.translate_cached(&mut translator, cmd) {
+            return translator;
+        }
+        // Everything else expects the whole stack in RAM, and a label may be
+        // reached from anywhere
+        self.spill(&mut translator);
         match cmd {
             // Pointer
             Command::Push(Segment {
@@ -92,50 +230,231 @@ impl Babel {
                 index,
             }) => push_static(&mut translator, *index as u32, &self.basename),
 
-            // Catch unimplemented
-            Command::Pop(..) => {
-                todo!()
+            Command::Pop(Segment {
+                segment: SegmentType::Constant,
+                ..
+            }) => unreachable!("pop constant is rejected by validate_command"),
+
+            // Program flow
+            Command::Label(l) => label(&mut translator, self.scoped_label(l)),
+            Command::Goto(l) => goto(&mut translator, self.scoped_label(l)),
+            Command::IfGoto(l) => if_goto(&mut translator, self.scoped_label(l)),
+
+            // Function calling
+            Command::Function(name, n_locals) => {
+                self.function = Some(name.clone());
+                function(&mut translator, name.clone(), *n_locals);
             }
+            Command::Call(name, n_args) => {
+                let return_label = self.return_label();
+                if self.compact {
+                    self.routines.insert(Routine::Call);
+                    call_shared(&mut translator, name.clone(), *n_args, return_label);
+                } else {
+                    call(&mut translator, name.clone(), *n_args, return_label);
+                }
+            }
+            Command::Return if self.compact => {
+                self.routines.insert(Routine::Return);
+                translator.with_asm([
+                    Assembly::addr_sym(Routine::Return.label()),
+                    Assembly::jump(Comp::Zero, Jump::JMP),
+                ]);
+            }
+            Command::Return => return_(&mut translator),
 
             Command::Add => {
-                translator.push(Assembly::comment("addition"));
                 translator.binary_asm(Comp::DplusM);
             }
             Command::Subtract => {
-                translator.push(Assembly::comment("subtract"));
                 translator.binary_asm(Comp::MminusD);
             }
             Command::Equal => {
-                translator.push(Assembly::comment("equal"));
-                translator.ord_asm(&mut self.counter, Jump::JEQ);
+                self.comparison(&mut translator, Jump::JEQ);
             }
             Command::LessThan => {
-                translator.push(Assembly::comment("less than"));
-                translator.ord_asm(&mut self.counter, Jump::JGT);
+                self.comparison(&mut translator, Jump::JGT);
             }
             Command::GreaterThan => {
-                translator.push(Assembly::comment("greater than"));
-                translator.ord_asm(&mut self.counter, Jump::JLT);
+                self.comparison(&mut translator, Jump::JLT);
             }
             Command::Negate => {
-                translator.push(Assembly::comment("negation"));
                 translator.unary_asm(Comp::NegateM);
             }
             Command::Not => {
-                translator.push(Assembly::comment("not"));
                 translator.unary_asm(Comp::NotM);
             }
             Command::And => {
-                translator.push(Assembly::comment("and cmd"));
                 translator.binary_asm(Comp::DandM);
             }
             Command::Or => {
-                translator.push(Assembly::comment("or cmd"));
                 translator.binary_asm(Comp::DorM);
             }
         }
         translator
     }
+
+    /// Whether [`Babel::translate_cached`] takes the top operand of `cmd`
+    /// from D rather than spilling it first
+    fn takes_top_from_d(&self, cmd: &Command) -> bool {
+        match cmd {
+            Command::Pop(_)
+            | Command::Negate
+            | Command::Not
+            | Command::Add
+            | Command::Subtract
+            | Command::And
+            | Command::Or
+            | Command::IfGoto(_) => true,
+            Command::Equal | Command::LessThan | Command::GreaterThan => !self.compact,
+            _ => false,
+        }
+    }
+
+    /// Translate `cmd` taking its top operand from D and leaving its result
+    /// there, or return false if it needs the stack in RAM
+    fn translate_cached(&mut self, translator: &mut Translation, cmd: &Command) -> bool {
+        match cmd {
+            Command::Push(Segment { segment, index }) => {
+                self.spill(translator);
+                match segment {
+                    SegmentType::Constant => {
+                        translator.with_asm([
+                            Assembly::Address(*index as u32),
+                            Assembly::assign(Dest::D, Comp::A),
+                        ]);
+                    }
+                    SegmentType::LATT(latt) => load_latt(translator, *latt, *index as u32),
+                    SegmentType::Temp => load_temp(translator, *index as u32),
+                    SegmentType::Static => load_static(translator, *index as u32, &self.basename),
+                    SegmentType::Pointer => load_pointer(translator, *index),
+                };
+                self.top_in_d = true;
+            }
+            Command::Pop(Segment { segment, index }) => {
+                self.fill(translator);
+                match segment {
+                    SegmentType::Constant => {
+                        unreachable!("pop constant is rejected by validate_command")
+                    }
+                    SegmentType::LATT(latt) => store_latt(translator, *latt, *index as u32),
+                    SegmentType::Temp => store_temp(translator, *index as u32),
+                    SegmentType::Static => store_static(translator, *index as u32, &self.basename),
+                    SegmentType::Pointer => store_pointer(translator, *index),
+                }
+            }
+            Command::Negate | Command::Not => {
+                self.fill(translator);
+                let comp = match cmd {
+                    Command::Negate => Comp::NegateD,
+                    _ => Comp::NotD,
+                };
+                translator.with_asm([Assembly::assign(Dest::D, comp)]);
+                self.top_in_d = true;
+            }
+            Command::Add | Command::Subtract | Command::And | Command::Or => {
+                // y is in D, x is the new top of the stack
+                self.fill(translator);
+                let comp = match cmd {
+                    Command::Add => Comp::DplusM,
+                    Command::Subtract => Comp::MminusD,
+                    Command::And => Comp::DandM,
+                    _ => Comp::DorM,
+                };
+                translator.with_asm([
+                    Assembly::sp(),
+                    Assembly::assign(Dest::AM, Comp::Mminus1),
+                    Assembly::assign(Dest::D, comp),
+                ]);
+                self.top_in_d = true;
+            }
+            Command::Equal | Command::LessThan | Command::GreaterThan if !self.compact => {
+                self.fill(translator);
+                let jump = match cmd {
+                    Command::Equal => Jump::JEQ,
+                    Command::LessThan => Jump::JGT,
+                    _ => Jump::JLT,
+                };
+                translator.ord_cached(&mut self.counter, jump);
+                self.top_in_d = true;
+            }
+            Command::IfGoto(l) => {
+                self.fill(translator);
+                translator.with_asm([
+                    Assembly::addr_sym(self.scoped_label(l)),
+                    Assembly::jump(Comp::D, Jump::JNE),
+                ]);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Inline the comparison, or in compact mode call its shared routine with
+    /// the return address in R15
+    fn comparison(&mut self, translator: &mut Translation, jump: Jump) {
+        if !self.compact {
+            translator.ord_asm(&mut self.counter, jump);
+            return;
+        }
+        self.routines.insert(Routine::Compare(jump));
+        let return_label = self.return_label();
+        translator.with_asm([
+            Assembly::addr_sym(return_label.clone()),
+            Assembly::assign(Dest::D, Comp::A),
+            Assembly::reg15(),
+            Assembly::assign(Dest::M, Comp::D),
+            Assembly::addr_sym(Routine::Compare(jump).label()),
+            Assembly::jump(Comp::Zero, Jump::JMP),
+            Assembly::label(return_label),
+        ]);
+    }
+
+    /// The shared routines used in compact mode, placed after the end of the
+    /// program
+    pub fn routines(&mut self) -> Translation {
+        let mut translator = Translation::new();
+        for routine in std::mem::take(&mut self.routines) {
+            translator.push(Assembly::label(routine.label()));
+            match routine {
+                Routine::Compare(jump) => {
+                    translator.ord_asm(&mut self.counter, jump);
+                    translator.with_asm([
+                        Assembly::reg15(),
+                        Assembly::assign(Dest::A, Comp::M),
+                        Assembly::jump(Comp::Zero, Jump::JMP),
+                    ]);
+                }
+                Routine::Call => call_routine(&mut translator),
+                Routine::Return => return_(&mut translator),
+            }
+        }
+        translator
+    }
+}
+
+/// Code shared by every use of a command in compact mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Routine {
+    /// Pops two values and pushes the comparison, returning to R15
+    Compare(Jump),
+    /// See [`call_shared`]
+    Call,
+    Return,
+}
+
+impl Routine {
+    /// `$` cannot appear in VM names, so these never clash with a function
+    fn label(self) -> &'static str {
+        match self {
+            Routine::Compare(Jump::JEQ) => "$eq",
+            Routine::Compare(Jump::JGT) => "$lt",
+            Routine::Compare(Jump::JLT) => "$gt",
+            Routine::Compare(jump) => unreachable!("not a comparison: {jump:?}"),
+            Routine::Call => "$call",
+            Routine::Return => "$return",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -166,6 +485,15 @@ impl Translation {
         self.with_asm([Assembly::sp(), Assembly::assign(Dest::M, Comp::Mplus1)]);
     }
 
+    /// `@SP`, `AM=M-1`, `D=M`
+    pub fn pop_to_dreg(&mut self) {
+        self.with_asm([
+            Assembly::sp(),
+            Assembly::assign(Dest::AM, Comp::Mminus1),
+            Assembly::assign(Dest::D, Comp::M),
+        ]);
+    }
+
     pub fn decrement_sp(&mut self) {
         self.with_asm([Assembly::sp(), Assembly::assign(Dest::M, Comp::Mminus1)]);
     }
@@ -182,12 +510,12 @@ impl Translation {
     pub fn finish() -> Self {
         let mut t = Self::new();
         t.with_asm([
-            Assembly::label("END"),
-            Assembly::addr_sym("END"),
+            Assembly::label(END),
+            Assembly::addr_sym(END),
             Assembly::Command {
-                dest: None,
+                dest: Dest::None,
                 comp: Comp::Zero,
-                jump: Some(Jump::JMP),
+                jump: Jump::JMP,
             },
         ]);
         t
@@ -199,7 +527,7 @@ impl Translation {
     }
 
     fn comment(&mut self, cmd: &Command) -> &mut Self {
-        self.push(Assembly::comment(format!("{cmd:?}")))
+        self.push(Assembly::comment(cmd.to_string()))
     }
 
     pub fn with_asm<I>(&mut self, iter: I) -> &mut Self
@@ -212,7 +540,6 @@ impl Translation {
 
     fn unary_asm(&mut self, m_comp: Comp) -> &mut Self {
         self.with_asm([
-            Assembly::comment(format!("Unary {}", m_comp)),
             // @SP
             Assembly::sp(),
             // M = M - 1 // Decrement to go to next value
@@ -270,30 +597,36 @@ impl Translation {
     /// Counter is used to generate unique jump locations
     fn ord_asm(&mut self, counter: &mut usize, jump: Jump) -> &mut Self {
         *counter += 1;
+        if jump == Jump::JEQ {
+            self.with_asm([
+                // @SP
+                Assembly::sp(),
+                // M = M - 1 // Decrement to go to next value
+                Assembly::assign(Dest::M, Comp::Mminus1),
+                // A = M
+                Assembly::assign(Dest::A, Comp::M),
+                // D = M
+                Assembly::assign(Dest::D, Comp::M),
+                // @SP
+                Assembly::sp(),
+                // M = M - 1
+                Assembly::assign(Dest::M, Comp::Mminus1),
+                // A = M
+                Assembly::assign(Dest::A, Comp::M),
+                // D = D - M
+                Assembly::assign(Dest::D, Comp::DminusM),
+            ]);
+        } else {
+            self.difference_asm(*counter);
+        }
         self.with_asm([
-            // @SP
-            Assembly::sp(),
-            // M = M - 1 // Decrement to go to next value
-            Assembly::assign(Dest::M, Comp::Mminus1),
-            // A = M
-            Assembly::assign(Dest::A, Comp::M),
-            // D = M
-            Assembly::assign(Dest::D, Comp::M),
-            // @SP
-            Assembly::sp(),
-            // M = M - 1
-            Assembly::assign(Dest::M, Comp::Mminus1),
-            // A = M
-            Assembly::assign(Dest::A, Comp::M),
-            // D = D - M
-            Assembly::assign(Dest::D, Comp::DminusM),
-            // @EQ{counter}
-            Assembly::addr_sym(format!("{:?}{}", jump, counter)),
+            // @$EQ{counter}
+            Assembly::addr_sym(format!("${jump:?}{counter}")),
             // D; JEQ/JLT/etc.
             Assembly::Command {
-                dest: None,
+                dest: Dest::None,
                 comp: Comp::D,
-                jump: Some(jump),
+                jump,
             },
             // From here the condition is false
             // @0
@@ -306,16 +639,16 @@ impl Translation {
             Assembly::assign(Dest::A, Comp::M),
             // M = D
             Assembly::assign(Dest::M, Comp::D),
-            // @AFTER{counter}
-            Assembly::addr_sym(format!("AFTER{}", counter)),
+            // @$AFTER{counter}
+            Assembly::addr_sym(format!("$AFTER{counter}")),
             // 0;JMP
             Assembly::Command {
-                dest: None,
+                dest: Dest::None,
                 comp: Comp::Zero,
-                jump: Some(Jump::JMP),
+                jump: Jump::JMP,
             },
-            // (EQ{counter}) // D = 0 here
-            Assembly::label(format!("{:?}{}", jump, counter)),
+            // ($EQ{counter}) // D = 0 here
+            Assembly::label(format!("${jump:?}{counter}")),
             // @0
             Assembly::Address(0),
             // D = A
@@ -326,8 +659,8 @@ impl Translation {
             Assembly::assign(Dest::A, Comp::M),
             // M = D
             Assembly::assign(Dest::M, Comp::Dminus1),
-            // (AFTER{counter})
-            Assembly::label(format!("AFTER{}", counter)),
+            // ($AFTER{counter})
+            Assembly::label(format!("$AFTER{counter}")),
             // @SP
             Assembly::sp(),
             // M = M + 1
@@ -335,6 +668,98 @@ impl Translation {
         ]);
         self
     }
+
+    /// Compare x on the stack with y in D, leaving true or false in D
+    fn ord_cached(&mut self, counter: &mut usize, jump: Jump) -> &mut Self {
+        *counter += 1;
+        if jump == Jump::JEQ {
+            // D = x - y, only its sign differs from y - x
+            self.with_asm([
+                Assembly::sp(),
+                Assembly::assign(Dest::AM, Comp::Mminus1),
+                Assembly::assign(Dest::D, Comp::MminusD),
+            ]);
+        } else {
+            self.store_dreg_in_reg13();
+            self.pop_to_dreg();
+            self.sign_asm(*counter);
+        }
+        self.with_asm([
+            Assembly::addr_sym(format!("${jump:?}{counter}")),
+            Assembly::jump(Comp::D, jump),
+            Assembly::assign(Dest::D, Comp::Zero),
+            Assembly::addr_sym(format!("$AFTER{counter}")),
+            Assembly::jump(Comp::Zero, Jump::JMP),
+            Assembly::label(format!("${jump:?}{counter}")),
+            Assembly::assign(Dest::D, Comp::MinusOne),
+            Assembly::label(format!("$AFTER{counter}")),
+        ])
+    }
+
+    /// Pop y then x, leaving a value with the sign of y - x in D
+    ///
+    /// y - x overflows when the operands have different signs, so that case is
+    /// decided by the signs alone. y is kept in R13 while x is inspected.
+    fn difference_asm(&mut self, counter: usize) -> &mut Self {
+        // y
+        self.pop_to_dreg();
+        self.store_dreg_in_reg13();
+        // x
+        self.pop_to_dreg();
+        self.sign_asm(counter)
+    }
+
+    /// With y in R13 and x in D and at `RAM[SP]`, leave a value with the sign
+    /// of y - x in D
+    fn sign_asm(&mut self, counter: usize) -> &mut Self {
+        self.with_asm([
+            // @$XNEG{counter}
+            Assembly::addr_sym(format!("$XNEG{counter}")),
+            // D; JLT
+            Assembly::jump(Comp::D, Jump::JLT),
+            // x >= 0, same sign unless y < 0
+            // @R13
+            Assembly::reg13(),
+            // D = M
+            Assembly::assign(Dest::D, Comp::M),
+            // @$SAME{counter}
+            Assembly::addr_sym(format!("$SAME{counter}")),
+            // D; JGE
+            Assembly::jump(Comp::D, Jump::JGE),
+            // D = -1 // y < 0 <= x
+            Assembly::assign(Dest::D, Comp::MinusOne),
+            // @$COMPARE{counter}
+            Assembly::addr_sym(format!("$COMPARE{counter}")),
+            // 0; JMP
+            Assembly::jump(Comp::Zero, Jump::JMP),
+            // ($XNEG{counter})
+            Assembly::label(format!("$XNEG{counter}")),
+            // @R13
+            Assembly::reg13(),
+            // D = M
+            Assembly::assign(Dest::D, Comp::M),
+            // @$SAME{counter}
+            Assembly::addr_sym(format!("$SAME{counter}")),
+            // D; JLT
+            Assembly::jump(Comp::D, Jump::JLT),
+            // D = 1 // x < 0 <= y
+            Assembly::assign(Dest::D, Comp::One),
+            // @$COMPARE{counter}
+            Assembly::addr_sym(format!("$COMPARE{counter}")),
+            // 0; JMP
+            Assembly::jump(Comp::Zero, Jump::JMP),
+            // ($SAME{counter}) // D = y here
+            Assembly::label(format!("$SAME{counter}")),
+            // @SP
+            Assembly::sp(),
+            // A = M
+            Assembly::assign(Dest::A, Comp::M),
+            // D = D - M
+            Assembly::assign(Dest::D, Comp::DminusM),
+            // ($COMPARE{counter})
+            Assembly::label(format!("$COMPARE{counter}")),
+        ])
+    }
 }
 
 impl IntoIterator for Translation {
@@ -353,6 +778,34 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn test_comparison_overflow() {
+        // x - y overflows for each pair, so its sign alone gives the wrong answer
+        for (x, y) in [(32767, -2), (-2, 32767), (-32767, 2), (2, -32767)] {
+            for cmd in [Command::GreaterThan, Command::LessThan] {
+                let mut commands = Vec::new();
+                for value in [x, y] {
+                    let constant = Segment::new(SegmentType::Constant, i32::abs(value));
+                    commands.push(Command::Push(constant));
+                    if value < 0 {
+                        commands.push(Command::Negate);
+                    }
+                }
+                let expected = match cmd {
+                    Command::GreaterThan => x > y,
+                    _ => x < y,
+                };
+                commands.push(cmd);
+                let sources = [crate::Source::new("Main", commands)];
+                let program = crate::translate(&sources, &Default::default()).unwrap();
+                let mut emulator = crate::Emulator::from_assembly(&program).unwrap();
+                emulator.poke(0, 256);
+                emulator.run(1000).unwrap();
+                assert_eq!(emulator.peek(256), -(expected as i16), "{x} {y}");
+            }
+        }
+    }
+
     #[test]
     fn test_parse() {
         assert_eq!(
@@ -364,5 +817,122 @@ mod test {
             Command::Pop(Segment::new(SegmentType::LATT(LATT::Local), 7))
         );
         assert_eq!("add".parse::<Command>().unwrap(), Command::Add);
+        assert_eq!(
+            "if-goto LOOP_START".parse::<Command>().unwrap(),
+            Command::IfGoto("LOOP_START".to_string())
+        );
+        assert!("goto 1LOOP".parse::<Command>().is_err());
+        assert!("label".parse::<Command>().is_err());
+        assert_eq!(
+            "function Main.fibonacci 2".parse::<Command>().unwrap(),
+            Command::Function("Main.fibonacci".to_string(), 2)
+        );
+        assert_eq!(
+            "call Main.fibonacci 1".parse::<Command>().unwrap(),
+            Command::Call("Main.fibonacci".to_string(), 1)
+        );
+        assert!("call Main.fibonacci".parse::<Command>().is_err());
+        assert_eq!("return".parse::<Command>().unwrap(), Command::Return);
+    }
+
+    #[test]
+    fn test_scoped_label() {
+        let mut babel = Babel::empty("Main");
+        babel.function = Some("Main.loop".to_string());
+        let asm = babel
+            .translate(&Command::Goto("END".to_string()))
+            .unwrap()
+            .into_iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>();
+        assert!(asm.contains(&"@Main.loop$END".to_string()));
+    }
+
+    #[test]
+    fn test_top_level_labels() {
+        // Names the translator uses for its own labels are free for programs,
+        // at the top level and inside functions
+        let commands = crate::parse(
+            "label END\nlabel JEQ1\nlabel AFTER1\nlabel eq\nlabel call\n\
+             push constant 1\npush constant 2\neq\ncall Main.f 0\ngoto END\n\
+             function Main.f 0\nlabel END\nlabel JEQ2\nlabel ret.1\nlabel Main.f\n\
+             push constant 1\npush constant 2\neq\ncall Main.f 0\ngoto END",
+        )
+        .unwrap();
+        let sources = [crate::Source::new("Main", commands)];
+        for compact in [false, true] {
+            let config = crate::Config {
+                compact,
+                ..Default::default()
+            };
+            let program = crate::translate(&sources, &config).unwrap();
+            assert!(crate::assemble(&program).is_ok(), "compact: {compact}");
+        }
+    }
+
+    #[test]
+    fn test_return_labels_distinct_from_program_labels() {
+        let commands =
+            crate::parse("function Main.main 0\ncall Main.main 0\nlabel ret.1\ngoto ret.1")
+                .unwrap();
+        let program =
+            crate::translate(&[crate::Source::new("Main", commands)], &Default::default()).unwrap();
+        assert!(crate::assemble(&program).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_commands_are_errors() {
+        let mut babel = Babel::empty("Main");
+        for cmd in ["pop constant 1", "push pointer 2", "call Main.f 40000"] {
+            assert!(babel.translate(&cmd.parse().unwrap()).is_err(), "{cmd}");
+        }
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let asm = Babel::empty("Sys")
+            .bootstrap(true)
+            .into_iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(asm[1..5], ["@256", "D=A", "@SP", "M=D"]);
+        assert_eq!(asm[5..9], ["@1", "D=-A", "@LCL", "M=D"]);
+        assert!(asm.contains(&"@Sys.init".to_string()));
+        assert_eq!(asm.last().unwrap(), "($ret.Bootstrap.1)");
+    }
+
+    #[test]
+    fn test_spill_before_comment() {
+        let mut babel = Babel::empty("Main");
+        babel.set_cache_top(true);
+        let mut asm = Vec::new();
+        for cmd in ["push constant 1", "neg", "label L", "push constant 2"] {
+            let translation = babel.translate(&cmd.parse().unwrap()).unwrap();
+            asm.push(
+                translation
+                    .into_iter()
+                    .map(|a| a.to_string())
+                    .collect::<Vec<_>>(),
+            );
+        }
+        // neg works on D, label pushes it, before its own comment
+        assert_eq!(asm[1][0], "// neg");
+        assert_eq!(asm[2].last().unwrap(), "(L)");
+        assert_eq!(asm[2][asm[2].len() - 2], "// label L");
+        assert!(asm[2].len() > 2, "no spill");
+        assert_eq!(asm[3][0], "// push constant 2");
+    }
+
+    #[test]
+    fn test_return_labels_unique() {
+        let mut babel = Babel::empty("Main");
+        babel
+            .translate(&Command::Function("Main.main".to_string(), 0))
+            .unwrap();
+        let call = Command::Call("Math.add".to_string(), 2);
+        let first = babel.translate(&call).unwrap().into_iter().last().unwrap();
+        let second = babel.translate(&call).unwrap().into_iter().last().unwrap();
+        assert_eq!(first.to_string(), "($ret.Main.main.1)");
+        assert_eq!(second.to_string(), "($ret.Main.main.2)");
     }
 }
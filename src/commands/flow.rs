@@ -0,0 +1,33 @@
+use crate::{
+    assembly::{Assembly, Comp, Dest, Jump},
+    babel::Translation,
+};
+
+pub fn label(translator: &mut Translation, label: String) {
+    translator.with_asm([Assembly::label(label)]);
+}
+
+pub fn goto(translator: &mut Translation, label: String) {
+    translator.with_asm([
+        Assembly::addr_sym(label),
+        Assembly::Command {
+            dest: Dest::None,
+            comp: Comp::Zero,
+            jump: Jump::JMP,
+        },
+    ]);
+}
+
+pub fn if_goto(translator: &mut Translation, label: String) {
+    // Pop top of stack into D, jump if it is not false (0)
+    translator.decrement_sp();
+    translator.store_sp_to_dreg();
+    translator.with_asm([
+        Assembly::addr_sym(label),
+        Assembly::Command {
+            dest: Dest::None,
+            comp: Comp::D,
+            jump: Jump::JNE,
+        },
+    ]);
+}
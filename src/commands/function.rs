@@ -0,0 +1,181 @@
+use crate::{
+    assembly::{Assembly, Comp, Dest, Jump},
+    babel::Translation,
+};
+
+pub fn function(translator: &mut Translation, name: String, n_locals: u32) {
+    translator.with_asm([Assembly::label(name)]);
+    // Zero-initialise the local segment by pushing 0 n_locals times
+    for _ in 0..n_locals {
+        translator.with_asm([
+            Assembly::sp(),
+            Assembly::assign(Dest::A, Comp::M),
+            Assembly::assign(Dest::M, Comp::Zero),
+        ]);
+        translator.increment_sp();
+    }
+}
+
+pub fn call(translator: &mut Translation, name: String, n_args: u32, return_label: String) {
+    // Push return address
+    translator.with_asm([
+        Assembly::addr_sym(return_label.clone()),
+        Assembly::assign(Dest::D, Comp::A),
+    ]);
+    translator.store_dreg_to_sp();
+    translator.increment_sp();
+
+    // Save caller's frame
+    for base in [
+        Assembly::local(),
+        Assembly::argument(),
+        Assembly::this(),
+        Assembly::that(),
+    ] {
+        translator.with_asm([base, Assembly::assign(Dest::D, Comp::M)]);
+        translator.store_dreg_to_sp();
+        translator.increment_sp();
+    }
+
+    translator.with_asm([
+        // ARG = SP - 5 - n_args
+        Assembly::sp(),
+        Assembly::assign(Dest::D, Comp::M),
+        Assembly::Address(5 + n_args),
+        Assembly::assign(Dest::D, Comp::DminusA),
+        Assembly::argument(),
+        Assembly::assign(Dest::M, Comp::D),
+        // LCL = SP
+        Assembly::sp(),
+        Assembly::assign(Dest::D, Comp::M),
+        Assembly::local(),
+        Assembly::assign(Dest::M, Comp::D),
+        // goto function
+        Assembly::addr_sym(name),
+        Assembly::Command {
+            dest: Dest::None,
+            comp: Comp::Zero,
+            jump: Jump::JMP,
+        },
+        // (return address)
+        Assembly::label(return_label),
+    ]);
+}
+
+/// Call through the shared `$call` routine, see [`call_routine`]
+///
+/// The return address goes in R15, `5 + n_args` in R14 and the target in D.
+pub fn call_shared(translator: &mut Translation, name: String, n_args: u32, return_label: String) {
+    translator.with_asm([
+        Assembly::addr_sym(return_label.clone()),
+        Assembly::assign(Dest::D, Comp::A),
+        Assembly::reg15(),
+        Assembly::assign(Dest::M, Comp::D),
+        Assembly::Address(5 + n_args),
+        Assembly::assign(Dest::D, Comp::A),
+        Assembly::reg14(),
+        Assembly::assign(Dest::M, Comp::D),
+        Assembly::addr_sym(name),
+        Assembly::assign(Dest::D, Comp::A),
+        Assembly::addr_sym("$call"),
+        Assembly::jump(Comp::Zero, Jump::JMP),
+        Assembly::label(return_label),
+    ]);
+}
+
+/// Body of the `$call` routine, the frame setup of [`call`] with its
+/// operands taken from registers
+pub fn call_routine(translator: &mut Translation) {
+    // R13 = target
+    translator.store_dreg_in_reg13();
+
+    // Push return address and the caller's frame
+    for base in [
+        Assembly::reg15(),
+        Assembly::local(),
+        Assembly::argument(),
+        Assembly::this(),
+        Assembly::that(),
+    ] {
+        translator.with_asm([base, Assembly::assign(Dest::D, Comp::M)]);
+        translator.store_dreg_to_sp();
+        translator.increment_sp();
+    }
+
+    translator.with_asm([
+        // ARG = SP - (5 + n_args)
+        Assembly::reg14(),
+        Assembly::assign(Dest::D, Comp::M),
+        Assembly::sp(),
+        Assembly::assign(Dest::D, Comp::MminusD),
+        Assembly::argument(),
+        Assembly::assign(Dest::M, Comp::D),
+        // LCL = SP
+        Assembly::sp(),
+        Assembly::assign(Dest::D, Comp::M),
+        Assembly::local(),
+        Assembly::assign(Dest::M, Comp::D),
+        // goto target
+        Assembly::reg13(),
+        Assembly::assign(Dest::A, Comp::M),
+        Assembly::jump(Comp::Zero, Jump::JMP),
+    ]);
+}
+
+pub fn return_(translator: &mut Translation) {
+    translator.with_asm([
+        // R13 = frame = LCL
+        Assembly::local(),
+        Assembly::assign(Dest::D, Comp::M),
+    ]);
+    translator.store_dreg_in_reg13();
+    translator.with_asm([
+        // R14 = return address = *(frame - 5)
+        Assembly::Address(5),
+        Assembly::assign(Dest::A, Comp::DminusA),
+        Assembly::assign(Dest::D, Comp::M),
+        Assembly::reg14(),
+        Assembly::assign(Dest::M, Comp::D),
+    ]);
+
+    // *ARG = pop()
+    translator.decrement_sp();
+    translator.store_sp_to_dreg();
+    translator.with_asm([
+        Assembly::argument(),
+        Assembly::assign(Dest::A, Comp::M),
+        Assembly::assign(Dest::M, Comp::D),
+        // SP = ARG + 1
+        Assembly::argument(),
+        Assembly::assign(Dest::D, Comp::Mplus1),
+        Assembly::sp(),
+        Assembly::assign(Dest::M, Comp::D),
+    ]);
+
+    // Restore THAT, THIS, ARG, LCL from *(frame - 1) .. *(frame - 4)
+    for base in [
+        Assembly::that(),
+        Assembly::this(),
+        Assembly::argument(),
+        Assembly::local(),
+    ] {
+        translator.with_asm([
+            Assembly::reg13(),
+            Assembly::assign(Dest::AM, Comp::Mminus1),
+            Assembly::assign(Dest::D, Comp::M),
+            base,
+            Assembly::assign(Dest::M, Comp::D),
+        ]);
+    }
+
+    // goto return address
+    translator.with_asm([
+        Assembly::reg14(),
+        Assembly::assign(Dest::A, Comp::M),
+        Assembly::Command {
+            dest: Dest::None,
+            comp: Comp::Zero,
+            jump: Jump::JMP,
+        },
+    ]);
+}
@@ -4,7 +4,8 @@ use crate::{
     commands::segment::LATT,
 };
 
-pub fn push_latt(translator: &mut Translation, latt: LATT, index: u32) {
+/// D = segment[index]
+pub fn load_latt(translator: &mut Translation, latt: LATT, index: u32) {
     let base = latt.as_asm();
     translator.with_asm([
         // store base (RAM[segment addr]) + index into dreg
@@ -14,10 +15,43 @@ pub fn push_latt(translator: &mut Translation, latt: LATT, index: u32) {
         Assembly::assign(Dest::A, Comp::DplusA),
         Assembly::assign(Dest::D, Comp::M),
     ]);
+}
+
+pub fn push_latt(translator: &mut Translation, latt: LATT, index: u32) {
+    load_latt(translator, latt, index);
     translator.store_dreg_to_sp();
     translator.increment_sp();
 }
 
+/// segment[index] = D
+pub fn store_latt(translator: &mut Translation, latt: LATT, index: u32) {
+    if index < 10 {
+        // Step A up to the address, cheaper than saving D for small indices
+        translator.with_asm([latt.as_asm(), Assembly::assign(Dest::A, Comp::M)]);
+        for _ in 0..index {
+            translator.with_asm([Assembly::assign(Dest::A, Comp::Aplus1)]);
+        }
+        translator.with_asm([Assembly::assign(Dest::M, Comp::D)]);
+        return;
+    }
+    translator.store_dreg_in_reg13();
+    translator.with_asm([
+        // R14 = base + index
+        latt.as_asm(),
+        Assembly::assign(Dest::D, Comp::M),
+        Assembly::Address(index),
+        Assembly::assign(Dest::D, Comp::DplusA),
+        Assembly::reg14(),
+        Assembly::assign(Dest::M, Comp::D),
+        // *R14 = R13
+        Assembly::reg13(),
+        Assembly::assign(Dest::D, Comp::M),
+        Assembly::reg14(),
+        Assembly::assign(Dest::A, Comp::M),
+        Assembly::assign(Dest::M, Comp::D),
+    ]);
+}
+
 pub fn pop_latt(translator: &mut Translation, latt: LATT, index: u32) {
     let base = latt.as_asm();
     // Store Address of base + i into dreg
@@ -1,15 +1,24 @@
-use std::str::FromStr;
+use std::{collections::BTreeSet, fmt::Display, str::FromStr};
 
-pub mod latt;
-pub mod pointer;
+pub(crate) mod flow;
+pub(crate) mod function;
+pub(crate) mod latt;
+pub(crate) mod pointer;
 pub mod segment;
-pub mod statics;
-pub mod temp;
+pub mod source;
+pub(crate) mod statics;
+pub(crate) mod temp;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Push(segment::Segment),
     Pop(segment::Segment),
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    Function(String, u32),
+    Call(String, u32),
+    Return,
     Add,
     Subtract,
     Negate,
@@ -27,6 +36,106 @@ pub enum ParseError {
     InvalidCommand(String),
     #[error("not a valid segment: {0}")]
     InvalidSegment(String),
+    #[error("missing segment: {0}")]
+    MissingSegment(String),
+    /// Also used for the local/argument counts of `function` and `call`
+    #[error("missing index: {0}")]
+    MissingIndex(String),
+    #[error("index is not a number: {0}")]
+    NonNumericIndex(String),
+    #[error("index must not be negative: {0}")]
+    NegativeIndex(String),
+    #[error("missing label: {0}")]
+    MissingLabel(String),
+    #[error("not a valid label: {0}")]
+    InvalidLabel(String),
+    #[error("unexpected trailing input: {0}")]
+    TrailingTokens(String),
+}
+
+impl ParseError {
+    /// Byte offset and length of the offending text within the command `s`
+    pub fn span_in(&self, s: &str) -> (usize, usize) {
+        let token = match self {
+            ParseError::InvalidCommand(_) => return (0, s.len()),
+            // Point just past the end of the command
+            ParseError::MissingSegment(_)
+            | ParseError::MissingIndex(_)
+            | ParseError::MissingLabel(_) => return (s.len(), 1),
+            // Trailing input is always the end of the command
+            ParseError::TrailingTokens(rest) => return (s.len() - rest.len(), rest.len()),
+            ParseError::InvalidSegment(token)
+            | ParseError::NonNumericIndex(token)
+            | ParseError::NegativeIndex(token)
+            | ParseError::InvalidLabel(token) => token,
+        };
+        // Skip the command keyword, the offending text is always an operand
+        s.split_ascii_whitespace()
+            .skip(1)
+            .find(|word| word == token)
+            .map(|word| (word.as_ptr() as usize - s.as_ptr() as usize, word.len()))
+            .unwrap_or((0, s.len()))
+    }
+}
+
+/// VM true and false
+fn truth(b: bool) -> i16 {
+    if b {
+        -1
+    } else {
+        0
+    }
+}
+
+impl Command {
+    /// What `neg` and `not` do to the top of the stack, in 16-bit two's complement
+    pub fn unary(&self) -> Option<fn(i16) -> i16> {
+        match self {
+            Command::Negate => Some(i16::wrapping_neg),
+            Command::Not => Some(|x| !x),
+            _ => None,
+        }
+    }
+
+    /// What a binary arithmetic or comparison command computes from x and y,
+    /// y being the top of the stack
+    pub fn binary(&self) -> Option<fn(i16, i16) -> i16> {
+        match self {
+            Command::Add => Some(i16::wrapping_add),
+            Command::Subtract => Some(i16::wrapping_sub),
+            Command::And => Some(|x, y| x & y),
+            Command::Or => Some(|x, y| x | y),
+            Command::Equal => Some(|x, y| truth(x == y)),
+            Command::GreaterThan => Some(|x, y| truth(x > y)),
+            Command::LessThan => Some(|x, y| truth(x < y)),
+            _ => None,
+        }
+    }
+}
+
+/// Writes the command back as VM source text
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Push(s) => write!(f, "push {} {}", s.segment, s.index),
+            Command::Pop(s) => write!(f, "pop {} {}", s.segment, s.index),
+            Command::Label(l) => write!(f, "label {l}"),
+            Command::Goto(l) => write!(f, "goto {l}"),
+            Command::IfGoto(l) => write!(f, "if-goto {l}"),
+            Command::Function(name, n_locals) => write!(f, "function {name} {n_locals}"),
+            Command::Call(name, n_args) => write!(f, "call {name} {n_args}"),
+            Command::Return => write!(f, "return"),
+            Command::Add => write!(f, "add"),
+            Command::Subtract => write!(f, "sub"),
+            Command::Negate => write!(f, "neg"),
+            Command::Equal => write!(f, "eq"),
+            Command::GreaterThan => write!(f, "gt"),
+            Command::LessThan => write!(f, "lt"),
+            Command::And => write!(f, "and"),
+            Command::Or => write!(f, "or"),
+            Command::Not => write!(f, "not"),
+        }
+    }
 }
 
 impl FromStr for Command {
@@ -34,27 +143,232 @@ impl FromStr for Command {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut ss = s.split_ascii_whitespace();
-        match ss.next() {
+        let cmd = match ss.next() {
             Some("push") => {
-                let segment = ss.next().unwrap().parse::<segment::SegmentType>()?;
-                let location = ss.next().unwrap().parse::<i32>().unwrap();
-                Ok(Command::Push(segment::Segment::new(segment, location)))
+                let segment = parse_segment(&mut ss, s)?;
+                let location = parse_index(&mut ss, s)?;
+                Command::Push(segment::Segment::new(segment, location))
             }
             Some("pop") => {
-                let segment = ss.next().unwrap().parse::<segment::SegmentType>()?;
-                let location = ss.next().unwrap().parse::<i32>().unwrap();
-                Ok(Command::Pop(segment::Segment::new(segment, location)))
+                let segment = parse_segment(&mut ss, s)?;
+                let location = parse_index(&mut ss, s)?;
+                Command::Pop(segment::Segment::new(segment, location))
+            }
+            Some("label") => Command::Label(parse_label(&mut ss, s)?),
+            Some("goto") => Command::Goto(parse_label(&mut ss, s)?),
+            Some("if-goto") => Command::IfGoto(parse_label(&mut ss, s)?),
+            Some("function") => {
+                let name = parse_label(&mut ss, s)?;
+                let n_locals = parse_index(&mut ss, s)? as u32;
+                Command::Function(name, n_locals)
             }
-            Some("add") => Ok(Command::Add),
-            Some("sub") => Ok(Command::Subtract),
-            Some("eq") => Ok(Command::Equal),
-            Some("lt") => Ok(Command::LessThan),
-            Some("gt") => Ok(Command::GreaterThan),
-            Some("neg") => Ok(Command::Negate),
-            Some("not") => Ok(Command::Not),
-            Some("or") => Ok(Command::Or),
-            Some("and") => Ok(Command::And),
-            _ => Err(ParseError::InvalidCommand(format!("Not valid: {}", s))),
+            Some("call") => {
+                let name = parse_label(&mut ss, s)?;
+                let n_args = parse_index(&mut ss, s)? as u32;
+                Command::Call(name, n_args)
+            }
+            Some("return") => Command::Return,
+            Some("add") => Command::Add,
+            Some("sub") => Command::Subtract,
+            Some("eq") => Command::Equal,
+            Some("lt") => Command::LessThan,
+            Some("gt") => Command::GreaterThan,
+            Some("neg") => Command::Negate,
+            Some("not") => Command::Not,
+            Some("or") => Command::Or,
+            Some("and") => Command::And,
+            _ => return Err(ParseError::InvalidCommand(s.to_string())),
+        };
+        if let Some(extra) = ss.next() {
+            let offset = extra.as_ptr() as usize - s.as_ptr() as usize;
+            return Err(ParseError::TrailingTokens(s[offset..].to_string()));
         }
+        Ok(cmd)
+    }
+}
+
+/// Check every command, see [`validate_command`]
+pub fn validate(commands: &[Command]) -> Result<(), segment::SegmentError> {
+    commands.iter().try_for_each(validate_command)
+}
+
+/// Check one command's segment index, and that its arguments or locals fit
+/// on the stack
+pub fn validate_command(cmd: &Command) -> Result<(), segment::SegmentError> {
+    let fits = |name: &str, what, count| {
+        if count <= segment::STACK_SIZE {
+            Ok(())
+        } else {
+            Err(segment::SegmentError::StackOverflow {
+                name: name.to_string(),
+                what,
+                count,
+            })
+        }
+    };
+    match cmd {
+        Command::Pop(segment::Segment {
+            segment: segment::SegmentType::Constant,
+            ..
+        }) => Err(segment::SegmentError::PopConstant),
+        Command::Push(segment) | Command::Pop(segment) => segment.validate(),
+        Command::Function(name, n_locals) => fits(name, "locals", *n_locals),
+        Command::Call(name, n_args) => fits(name, "arguments", *n_args),
+        _ => Ok(()),
+    }
+}
+
+/// Number of distinct static variables used by one file's commands
+pub fn static_count(commands: &[Command]) -> usize {
+    commands
+        .iter()
+        .filter_map(|cmd| match cmd {
+            Command::Push(segment::Segment {
+                segment: segment::SegmentType::Static,
+                index,
+            })
+            | Command::Pop(segment::Segment {
+                segment: segment::SegmentType::Static,
+                index,
+            }) => Some(*index),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>()
+        .len()
+}
+
+fn parse_segment<'a, I>(ss: &mut I, s: &str) -> Result<segment::SegmentType, ParseError>
+where
+    I: Iterator<Item = &'a str>,
+{
+    ss.next()
+        .ok_or_else(|| ParseError::MissingSegment(s.to_string()))?
+        .parse::<segment::SegmentType>()
+}
+
+/// Non-negative index, also used for `function`/`call` counts
+fn parse_index<'a, I>(ss: &mut I, s: &str) -> Result<i32, ParseError>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let token = ss
+        .next()
+        .ok_or_else(|| ParseError::MissingIndex(s.to_string()))?;
+    match token.parse::<i32>() {
+        Ok(index) if index < 0 => Err(ParseError::NegativeIndex(token.to_string())),
+        Ok(index) => Ok(index),
+        Err(_) => Err(ParseError::NonNumericIndex(token.to_string())),
+    }
+}
+
+/// Labels may contain letters, digits, `_`, `.`, `:` and must not start with a digit
+fn parse_label<'a, I>(ss: &mut I, s: &str) -> Result<String, ParseError>
+where
+    I: Iterator<Item = &'a str>,
+{
+    match ss.next() {
+        Some(label)
+            if !label.starts_with(|c: char| c.is_ascii_digit())
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | ':')) =>
+        {
+            Ok(label.to_string())
+        }
+        Some(label) => Err(ParseError::InvalidLabel(label.to_string())),
+        None => Err(ParseError::MissingLabel(s.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            "push".parse::<Command>(),
+            Err(ParseError::MissingSegment(_))
+        ));
+        assert!(matches!(
+            "push local".parse::<Command>(),
+            Err(ParseError::MissingIndex(_))
+        ));
+        assert!(matches!(
+            "push constant abc".parse::<Command>(),
+            Err(ParseError::NonNumericIndex(t)) if t == "abc"
+        ));
+        assert!(matches!(
+            "pop local -1".parse::<Command>(),
+            Err(ParseError::NegativeIndex(t)) if t == "-1"
+        ));
+        assert!(matches!(
+            "add 3".parse::<Command>(),
+            Err(ParseError::TrailingTokens(t)) if t == "3"
+        ));
+        assert!(matches!(
+            "call Foo.bar -2".parse::<Command>(),
+            Err(ParseError::NegativeIndex(_))
+        ));
+        assert!(matches!(
+            "goto".parse::<Command>(),
+            Err(ParseError::MissingLabel(_))
+        ));
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for s in [
+            "push constant 7",
+            "pop that 2",
+            "push static 3",
+            "if-goto LOOP",
+            "function Main.main 2",
+            "call Math.multiply 2",
+            "return",
+            "sub",
+            "not",
+        ] {
+            assert_eq!(s.parse::<Command>().unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let parse = |s: &str| vec![s.parse::<Command>().unwrap()];
+        assert!(validate(&parse("push temp 7")).is_ok());
+        assert!(matches!(
+            validate(&parse("push temp 8")),
+            Err(segment::SegmentError::OutOfRange {
+                index: 8,
+                max: 7,
+                ..
+            })
+        ));
+        assert!(validate(&parse("pop pointer 2")).is_err());
+        assert!(validate(&parse("push constant 32767")).is_ok());
+        assert!(validate(&parse("push constant 40000")).is_err());
+        assert!(validate(&parse("push static 240")).is_err());
+        assert!(matches!(
+            validate(&parse("pop constant 0")),
+            Err(segment::SegmentError::PopConstant)
+        ));
+        assert_eq!(
+            static_count(&[
+                "push static 0".parse().unwrap(),
+                "pop static 0".parse().unwrap(),
+                "pop static 3".parse().unwrap(),
+            ]),
+            2
+        );
+    }
+
+    #[test]
+    fn test_span_in() {
+        let s = "push constant 1 2  3";
+        let err = s.parse::<Command>().unwrap_err();
+        assert_eq!(err.span_in(s), (16, 4));
+        let s = "push constant";
+        assert_eq!(s.parse::<Command>().unwrap_err().span_in(s), (13, 1));
     }
 }
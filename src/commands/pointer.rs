@@ -3,41 +3,33 @@ use crate::{
     babel::Translation,
 };
 
+fn pointee(idx: i32) -> Assembly {
+    match idx {
+        0 => Assembly::this(),
+        1 => Assembly::that(),
+        _ => unreachable!("pointer {idx} is rejected by validate_command"),
+    }
+}
+
+/// D = THIS/THAT
+pub fn load_pointer(translator: &mut Translation, idx: i32) {
+    translator.with_asm([pointee(idx), Assembly::assign(Dest::D, Comp::M)]);
+}
+
 pub fn push_pointer(translator: &mut Translation, idx: i32) {
-    let pointee = {
-        match idx {
-            0 => Assembly::this(),
-            1 => Assembly::that(),
-            _ => panic!("Invalid command: push pointer {idx}"),
-        }
-    };
-    translator.with_asm([
-        // @THIS/That
-        pointee,
-        Assembly::assign(Dest::D, Comp::M),
-        // Push onto stack
-        Assembly::sp(),
-        Assembly::assign(Dest::A, Comp::M),
-        Assembly::assign(Dest::M, Comp::D),
-        // Increment SP
-        Assembly::sp(),
-        Assembly::assign(Dest::M, Comp::Mplus1),
-    ]);
+    load_pointer(translator, idx);
+    translator.store_dreg_to_sp();
+    translator.increment_sp();
+}
+
+/// THIS/THAT = D
+pub fn store_pointer(translator: &mut Translation, idx: i32) {
+    translator.with_asm([pointee(idx), Assembly::assign(Dest::M, Comp::D)]);
 }
 
 pub fn pop_pointer(translator: &mut Translation, idx: i32) {
-    let pointee = {
-        match idx {
-            0 => Assembly::this(),
-            1 => Assembly::that(),
-            _ => panic!("Invalid command: pop pointer {idx}"),
-        }
-    };
     translator.decrement_sp();
     translator.store_sp_to_dreg();
-    translator.with_asm([
-        // Set THIS/THAT to D register
-        pointee,
-        Assembly::assign(Dest::M, Comp::D),
-    ]);
+    // Set THIS/THAT to D register
+    store_pointer(translator, idx);
 }
@@ -1,8 +1,9 @@
-use std::str::FromStr;
+use std::{fmt::Display, str::FromStr};
 
 use crate::{assembly::Assembly, commands::ParseError};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum LATT {
     Local,
     Argument,
@@ -22,6 +23,7 @@ impl LATT {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum SegmentType {
     LATT(LATT),
     Static,
@@ -40,6 +42,82 @@ impl Segment {
     pub fn new(segment: SegmentType, index: i32) -> Self {
         Self { segment, index }
     }
+
+    pub fn segment(&self) -> &SegmentType {
+        &self.segment
+    }
+
+    pub fn index(&self) -> i32 {
+        self.index
+    }
+
+    /// Check the index is addressable for this segment
+    pub fn validate(&self) -> Result<(), SegmentError> {
+        let max = self.segment.max_index();
+        if (0..=max).contains(&self.index) {
+            Ok(())
+        } else {
+            Err(SegmentError::OutOfRange {
+                segment: self.segment.clone(),
+                index: self.index,
+                max,
+            })
+        }
+    }
+}
+
+/// Static variables live in RAM[16..256]
+pub const MAX_STATICS: usize = 240;
+
+/// The stack lives in RAM[256..2048]
+pub const STACK_SIZE: u32 = 1792;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SegmentError {
+    #[error("index {index} out of range for {segment} segment, maximum is {max}")]
+    OutOfRange {
+        segment: SegmentType,
+        index: i32,
+        max: i32,
+    },
+    #[error("cannot pop into the constant segment")]
+    PopConstant,
+    #[error("{name} takes {count} {what}, more than the {STACK_SIZE}-word stack holds")]
+    StackOverflow {
+        name: String,
+        what: &'static str,
+        count: u32,
+    },
+    #[error("too many static variables: {0}, at most {MAX_STATICS} fit in RAM[16..256]")]
+    TooManyStatics(usize),
+}
+
+impl SegmentType {
+    /// Largest valid index, constants and LATT offsets are limited to a 15-bit A-instruction
+    pub fn max_index(&self) -> i32 {
+        match self {
+            SegmentType::LATT(_) | SegmentType::Constant => 32767,
+            SegmentType::Static => MAX_STATICS as i32 - 1,
+            SegmentType::Pointer => 1,
+            SegmentType::Temp => 7,
+        }
+    }
+}
+
+impl Display for SegmentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SegmentType::LATT(LATT::Argument) => "argument",
+            SegmentType::LATT(LATT::Local) => "local",
+            SegmentType::LATT(LATT::This) => "this",
+            SegmentType::LATT(LATT::That) => "that",
+            SegmentType::Static => "static",
+            SegmentType::Constant => "constant",
+            SegmentType::Pointer => "pointer",
+            SegmentType::Temp => "temp",
+        };
+        write!(f, "{name}")
+    }
 }
 
 impl FromStr for SegmentType {
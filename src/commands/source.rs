@@ -0,0 +1,107 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    commands::{Command, ParseError},
+    utils,
+};
+
+/// A [`ParseError`] together with where it happened in a VM source file
+#[derive(Debug, thiserror::Error)]
+pub struct SourceError {
+    pub file: PathBuf,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column of the start of the offending text
+    pub column: usize,
+    /// Length of the offending text, for underlining
+    pub len: usize,
+    pub source_line: String,
+    pub error: ParseError,
+}
+
+impl Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        writeln!(f, "{}", self.error)?;
+        writeln!(
+            f,
+            "{gutter}--> {}:{}:{}",
+            self.file.display(),
+            self.line,
+            self.column
+        )?;
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{} | {}", self.line, self.source_line)?;
+        // Keep tabs so the carets line up however wide the terminal draws them
+        let padding = self
+            .source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        write!(f, "{gutter} | {padding}{}", "^".repeat(self.len.max(1)))
+    }
+}
+
+/// Parse every command in a VM source file, skipping blank lines and comments
+pub fn parse_source<P: AsRef<Path>>(file: P, source: &str) -> Result<Vec<Command>, SourceError> {
+    Ok(parse_numbered(file, source)?
+        .into_iter()
+        .map(|(_, cmd)| cmd)
+        .collect())
+}
+
+/// Like [`parse_source`], with the 1-based line number of each command
+pub fn parse_numbered<P: AsRef<Path>>(
+    file: P,
+    source: &str,
+) -> Result<Vec<(usize, Command)>, SourceError> {
+    let mut commands = Vec::new();
+    for (idx, line) in source.lines().enumerate() {
+        if let Some(cmd) = utils::remove_whitespace_comments(line) {
+            match cmd.parse::<Command>() {
+                Ok(cmd) => commands.push((idx + 1, cmd)),
+                Err(error) => {
+                    // `cmd` is a subslice of `line`, so its offset gives the column
+                    let offset = cmd.as_ptr() as usize - line.as_ptr() as usize;
+                    let (start, len) = error.span_in(cmd);
+                    return Err(SourceError {
+                        file: file.as_ref().to_path_buf(),
+                        line: idx + 1,
+                        column: line[..offset + start].chars().count() + 1,
+                        len,
+                        source_line: line.to_string(),
+                        error,
+                    });
+                }
+            }
+        }
+    }
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_span() {
+        let source = "push constant 1\n  push locl 0 // oops\n";
+        let err = parse_source("Foo.vm", source).unwrap_err();
+        assert_eq!((err.line, err.column, err.len), (2, 8, 4));
+        assert_eq!(
+            err.to_string(),
+            "not a valid segment: locl\n --> Foo.vm:2:8\n  |\n2 |   push locl 0 // oops\n  |        ^^^^"
+        );
+    }
+
+    #[test]
+    fn test_invalid_command_span() {
+        let err = parse_source("Foo.vm", "\tfoo bar\n").unwrap_err();
+        assert_eq!((err.line, err.column, err.len), (1, 2, 7));
+        assert!(err.to_string().ends_with("1 | \tfoo bar\n  | \t^^^^^^^"));
+    }
+}
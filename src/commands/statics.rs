@@ -7,16 +7,26 @@ fn var_symbol(index: u32, basename: &str) -> Assembly {
     Assembly::VariableSymbol(std::borrow::Cow::Owned(format!("{basename}.{index}")))
 }
 
-pub fn push_static(translator: &mut Translation, index: u32, basename: &str) {
+/// D = static[index]
+pub fn load_static(translator: &mut Translation, index: u32, basename: &str) {
     let symbol = var_symbol(index, basename);
     translator.with_asm([symbol, Assembly::assign(Dest::D, Comp::M)]);
+}
+
+pub fn push_static(translator: &mut Translation, index: u32, basename: &str) {
+    load_static(translator, index, basename);
     translator.store_dreg_to_sp();
     translator.increment_sp();
 }
 
-pub fn pop_static(translator: &mut Translation, index: u32, basename: &str) {
+/// static[index] = D
+pub fn store_static(translator: &mut Translation, index: u32, basename: &str) {
     let symbol = var_symbol(index, basename);
+    translator.with_asm([symbol, Assembly::assign(Dest::M, Comp::D)]);
+}
+
+pub fn pop_static(translator: &mut Translation, index: u32, basename: &str) {
     translator.decrement_sp();
     translator.store_sp_to_dreg();
-    translator.with_asm([symbol, Assembly::assign(Dest::M, Comp::D)]);
+    store_static(translator, index, basename);
 }
@@ -3,23 +3,31 @@ use crate::{
     babel::Translation,
 };
 
-pub fn push_temp(translator: &mut Translation, index: u32) {
-    let temp_loc = 5 + index;
+/// D = temp[index]
+pub fn load_temp(translator: &mut Translation, index: u32) {
     translator.with_asm([
         // Store TEMP[i] into D
-        Assembly::Address(temp_loc),
+        Assembly::Address(5 + index),
         Assembly::assign(Dest::D, Comp::M),
     ]);
+}
+
+pub fn push_temp(translator: &mut Translation, index: u32) {
+    load_temp(translator, index);
     translator.store_dreg_to_sp();
     translator.increment_sp();
 }
 
-pub fn pop_temp(translator: &mut Translation, index: u32) {
-    let temp_loc = 5 + index;
-    translator.decrement_sp();
-    translator.store_sp_to_dreg();
+/// temp[index] = D
+pub fn store_temp(translator: &mut Translation, index: u32) {
     translator.with_asm([
-        Assembly::Address(temp_loc),
+        Assembly::Address(5 + index),
         Assembly::assign(Dest::M, Comp::D),
     ]);
 }
+
+pub fn pop_temp(translator: &mut Translation, index: u32) {
+    translator.decrement_sp();
+    translator.store_sp_to_dreg();
+    store_temp(translator, index);
+}
@@ -0,0 +1,513 @@
+//! Differential testing of the translator against the reference interpreter
+//!
+//! Random well-formed VM programs, with loops, jumps and calls, are run both
+//! by the [`Interpreter`] and, after translation and assembly, by the Hack
+//! [`Emulator`]. Any difference in final RAM is shrunk to a minimal program
+//! and reported.
+
+use std::fmt::Display;
+
+use crate::{
+    commands::{
+        segment::{Segment, SegmentType, LATT},
+        Command,
+    },
+    emulator::{Emulator, EmulatorError},
+    fold::fold,
+    interpreter::Interpreter,
+    peephole::optimize,
+    program::{translate, Config, Source},
+};
+
+/// Base addresses both machines start with
+const BASES: [(u16, i16); 5] = [(0, 256), (1, 300), (2, 400), (3, 3000), (4, 3010)];
+
+/// Scratch registers the generated code may use freely
+const SCRATCH: std::ops::Range<usize> = 13..16;
+
+const MAX_STEPS: u64 = 1_000_000;
+
+/// Small xorshift generator, so runs are reproducible from a seed
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift must not start at zero
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in `0..n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// How many values a command pops and pushes
+fn stack_effect(cmd: &Command) -> (usize, usize) {
+    match cmd {
+        Command::Push(_) => (0, 1),
+        Command::Pop(_) | Command::IfGoto(_) | Command::Return => (1, 0),
+        Command::Negate | Command::Not => (1, 1),
+        Command::Add
+        | Command::Subtract
+        | Command::Equal
+        | Command::GreaterThan
+        | Command::LessThan
+        | Command::And
+        | Command::Or => (2, 1),
+        Command::Call(_, n_args) => (*n_args as usize, 1),
+        Command::Label(_) | Command::Goto(_) | Command::Function(..) => (0, 0),
+    }
+}
+
+/// The stack never underflows along the program text, and control never
+/// falls into or out of a function or returns from the top level
+///
+/// Saved return addresses are the one thing the interpreter and the
+/// translation store differently, so programs that misuse them can't be
+/// compared.
+fn is_well_formed(program: &[Command]) -> bool {
+    let mut depth = 0;
+    let mut in_function = false;
+    for (i, cmd) in program.iter().enumerate() {
+        match cmd {
+            Command::Function(..) => {
+                let previous = i.checked_sub(1).map(|i| &program[i]);
+                if !matches!(previous, Some(Command::Goto(_) | Command::Return)) {
+                    return false;
+                }
+                in_function = true;
+                depth = 0;
+            }
+            Command::Return if !in_function => return false,
+            _ => {}
+        }
+        let (pops, pushes) = stack_effect(cmd);
+        if depth < pops {
+            return false;
+        }
+        depth = depth - pops + pushes;
+    }
+    // Running off the end of a function halts with its frame still live
+    !in_function || matches!(program.last(), Some(Command::Goto(_) | Command::Return))
+}
+
+fn constant(rng: &mut Rng) -> i32 {
+    // Bias towards edge cases
+    match rng.below(4) {
+        0 => [0, 1, 2, 16384, 32767][rng.below(5) as usize],
+        1 => rng.below(16) as i32,
+        _ => rng.below(32768) as i32,
+    }
+}
+
+/// Random segment, only indexing the arguments and locals a function has
+fn segment(rng: &mut Rng, push: bool, frame: Option<(u32, u32)>) -> Segment {
+    let (n_args, n_locals) = frame.unwrap_or((8, 8));
+    // Popping pointer would move THIS/THAT somewhere arbitrary
+    let choices = if push { 8 } else { 7 };
+    let (segment, index) = match rng.below(choices) {
+        0 if n_locals > 0 => (SegmentType::LATT(LATT::Local), rng.below(n_locals as u64)),
+        1 if n_args > 0 => (SegmentType::LATT(LATT::Argument), rng.below(n_args as u64)),
+        2 => (SegmentType::LATT(LATT::This), rng.below(8)),
+        3 => (SegmentType::LATT(LATT::That), rng.below(8)),
+        5 | 6 => (SegmentType::Static, rng.below(COUNTERS as u64)),
+        7 => (SegmentType::Pointer, rng.below(2)),
+        _ => (SegmentType::Temp, rng.below(8)),
+    };
+    Segment::new(segment, index as i32)
+}
+
+/// Statics from this index on are loop counters, which loop bodies never write
+const COUNTERS: i32 = 6;
+
+/// Deepest stack a block of code builds, so that the deepest chain of calls
+/// stays below the local segment at RAM[300]
+const MAX_DEPTH: usize = 12;
+
+/// Builds the parts of a random program, see [`generate`]
+struct Generator<'a> {
+    rng: &'a mut Rng,
+    /// Labels and loops so far, to keep both unique
+    labels: usize,
+    /// Functions the code being generated may call, with their argument counts
+    callees: Vec<(String, u32)>,
+    /// Argument and local counts of the function being generated
+    frame: Option<(u32, u32)>,
+}
+
+impl Generator<'_> {
+    /// Append about `len` commands to `out`, going from stack depth `depth`
+    /// to the returned depth and never popping below `floor`
+    ///
+    /// With `flow`, bounded loops and forward jumps over stack-neutral
+    /// blocks are generated too.
+    fn block(
+        &mut self,
+        out: &mut Vec<Command>,
+        len: usize,
+        mut depth: usize,
+        floor: usize,
+        flow: bool,
+    ) -> usize {
+        let end = out.len() + len;
+        while out.len() < end {
+            let rng = &mut *self.rng;
+            let room = depth < floor + MAX_DEPTH;
+            let cmd = match rng.below(16) {
+                0..=3 if room => Command::Push(Segment::new(SegmentType::Constant, constant(rng))),
+                4 if room => Command::Push(segment(rng, true, self.frame)),
+                5 if depth > floor => Command::Pop(segment(rng, false, self.frame)),
+                6 if depth > floor => {
+                    [Command::Negate, Command::Not][rng.below(2) as usize].clone()
+                }
+                7..=10 if depth >= floor + 2 => [
+                    Command::Add,
+                    Command::Subtract,
+                    Command::Equal,
+                    Command::GreaterThan,
+                    Command::LessThan,
+                    Command::And,
+                    Command::Or,
+                ][rng.below(7) as usize]
+                    .clone(),
+                11 | 12 if !self.callees.is_empty() => {
+                    let (name, n_args) =
+                        &self.callees[rng.below(self.callees.len() as u64) as usize];
+                    if depth < floor + *n_args as usize {
+                        continue;
+                    }
+                    Command::Call(name.clone(), *n_args)
+                }
+                13 if flow => {
+                    self.counted_loop(out, depth);
+                    continue;
+                }
+                14 | 15 if flow && room => {
+                    self.skip(out, depth);
+                    continue;
+                }
+                _ => continue,
+            };
+            let (pops, pushes) = stack_effect(&cmd);
+            depth = depth - pops + pushes;
+            out.push(cmd);
+        }
+        depth
+    }
+
+    /// A block leaving the stack as deep as it found it
+    fn neutral(&mut self, out: &mut Vec<Command>, depth: usize) {
+        let len = 2 + self.rng.below(6) as usize;
+        let end = self.block(out, len, depth, depth, false);
+        for _ in depth..end {
+            out.push(Command::Pop(segment(self.rng, false, self.frame)));
+        }
+    }
+
+    fn label(&mut self, prefix: &str) -> String {
+        self.labels += 1;
+        format!("{prefix}{}", self.labels)
+    }
+
+    /// Run a neutral block one to three times, counting down a static
+    fn counted_loop(&mut self, out: &mut Vec<Command>, depth: usize) {
+        let label = self.label("LOOP");
+        let counter = Segment::new(SegmentType::Static, COUNTERS + self.labels as i32);
+        let times = 1 + self.rng.below(3) as i32;
+        out.extend([
+            Command::Push(Segment::new(SegmentType::Constant, times)),
+            Command::Pop(counter.clone()),
+            Command::Label(label.clone()),
+        ]);
+        self.neutral(out, depth);
+        out.extend([
+            Command::Push(counter.clone()),
+            Command::Push(Segment::new(SegmentType::Constant, 1)),
+            Command::Subtract,
+            Command::Pop(counter.clone()),
+            Command::Push(counter),
+            Command::IfGoto(label),
+        ]);
+    }
+
+    /// Jump over a neutral block, always or depending on a value
+    fn skip(&mut self, out: &mut Vec<Command>, depth: usize) {
+        let label = self.label("SKIP");
+        if self.rng.below(4) == 0 {
+            out.push(Command::Goto(label.clone()));
+        } else {
+            out.push(Command::Push(segment(self.rng, true, self.frame)));
+            out.push(Command::IfGoto(label.clone()));
+        }
+        self.neutral(out, depth);
+        out.push(Command::Label(label));
+    }
+
+    fn function(&mut self, name: &str, n_args: u32, n_locals: u32, len: usize) -> Vec<Command> {
+        self.frame = Some((n_args, n_locals));
+        let mut out = vec![Command::Function(name.to_string(), n_locals)];
+        if self.block(&mut out, len, 0, 0, true) == 0 {
+            out.push(Command::Push(Segment::new(SegmentType::Constant, 1)));
+        }
+        out.push(Command::Return);
+        self.frame = None;
+        out
+    }
+}
+
+/// Random program of about `len` top-level commands, then up to two
+/// functions it calls
+///
+/// Functions only call those defined after them, and loops count down from
+/// at most three, so every program halts.
+pub fn generate(rng: &mut Rng, len: usize) -> Vec<Command> {
+    let mut generator = Generator {
+        rng,
+        labels: 0,
+        callees: Vec::new(),
+        frame: None,
+    };
+    let mut functions = Vec::new();
+    for i in (0..generator.rng.below(3)).rev() {
+        let name = format!("Fuzz.f{i}");
+        let (n_args, n_locals) = (generator.rng.below(3) as u32, generator.rng.below(3) as u32);
+        let mut function = generator.function(&name, n_args, n_locals, len / 4);
+        function.append(&mut functions);
+        functions = function;
+        generator.callees.push((name, n_args));
+    }
+    let mut program = Vec::with_capacity(len);
+    generator.block(&mut program, len, 0, 0, true);
+    program.extend([
+        Command::Label("HALT".to_string()),
+        Command::Goto("HALT".to_string()),
+    ]);
+    program.extend(functions);
+    program
+}
+
+/// How the translation's final state differs from the interpreter's
+#[derive(Debug, Clone, PartialEq)]
+pub enum Difference {
+    Ram {
+        address: usize,
+        /// Value according to the interpreter
+        expected: i16,
+        /// Value according to the translated code
+        actual: i16,
+    },
+    /// The translated code failed or did not halt where the interpreter halted
+    Emulator(EmulatorError),
+}
+
+/// A program whose final state differs between the interpreter and the emulator
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub program: Vec<Command>,
+    /// Whether comparisons, calls and returns used shared routines
+    pub compact: bool,
+    /// Whether constants were folded, the stack top cached in D and the
+    /// translation run through the peephole optimiser
+    pub optimized: bool,
+    pub difference: Difference,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.difference {
+            Difference::Ram {
+                address,
+                expected,
+                actual,
+            } => write!(f, "RAM[{address}] is {actual}, expected {expected},")?,
+            Difference::Emulator(error) => write!(f, "{error}")?,
+        }
+        writeln!(
+            f,
+            " after {}{}translation of:",
+            if self.compact { "compact " } else { "" },
+            if self.optimized { "optimised " } else { "" },
+        )?;
+        for cmd in &self.program {
+            writeln!(f, "    {cmd}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Run `program` on both machines, returning the first difference
+///
+/// The translation is checked as is, optimised, and in compact mode.
+/// Programs the interpreter rejects or that don't halt are not compared.
+pub fn compare(program: &[Command]) -> Option<Mismatch> {
+    let sources = [Source::new("Fuzz", program.to_vec())];
+
+    let mut interpreter = Interpreter::new(&sources).ok()?;
+    for (addr, value) in BASES {
+        interpreter.poke(addr, value);
+    }
+    let steps = interpreter.run(MAX_STEPS).ok()?;
+
+    for (compact, optimized) in [(false, false), (false, true), (true, false), (true, true)] {
+        let mismatch = |difference| Mismatch {
+            program: program.to_vec(),
+            compact,
+            optimized,
+            difference,
+        };
+        let config = Config {
+            compact,
+            cache_top: optimized,
+            ..Config::default()
+        };
+        let mut sources = sources.clone();
+        if optimized {
+            fold(&mut sources[0].commands);
+        }
+        let mut asm = translate(&sources, &config).expect("generated programs are valid");
+        if optimized {
+            optimize(&mut asm);
+        }
+        let mut emulator = Emulator::from_assembly(&asm).expect("generated programs assemble");
+        for (addr, value) in BASES {
+            emulator.poke(addr, value);
+        }
+        // No VM command takes more than a few dozen instructions
+        if let Err(error) = emulator.run(steps * 200 + 1000) {
+            return Some(mismatch(Difference::Emulator(error)));
+        }
+
+        // Words between SP and the local segment are dead, whatever the
+        // machines left there, since optimised code pushes fewer temporaries
+        // and call frames hold different return addresses
+        let sp = interpreter.peek(0).max(emulator.peek(0)) as usize;
+        let stack_end = BASES[1].1 as usize;
+        let live = (0..RAM_LIMIT)
+            .filter(|addr| !SCRATCH.contains(addr) && !(sp..stack_end).contains(addr));
+        for address in live {
+            let (expected, actual) = (interpreter.ram()[address], emulator.ram()[address]);
+            if expected != actual {
+                return Some(mismatch(Difference::Ram {
+                    address,
+                    expected,
+                    actual,
+                }));
+            }
+        }
+    }
+    None
+}
+
+/// Generated programs never touch RAM past the `that` segment
+const RAM_LIMIT: usize = 3020;
+
+/// Remove commands from a failing program while it keeps failing
+///
+/// Passes repeat until no single command can be removed, since dropping one
+/// command can make an earlier one redundant.
+pub fn shrink(mismatch: Mismatch) -> Mismatch {
+    let mut best = mismatch;
+    loop {
+        let len = best.program.len();
+        let mut chunk = len / 2;
+        while chunk >= 1 {
+            let mut start = 0;
+            while start < best.program.len() {
+                let mut candidate = best.program.clone();
+                candidate.drain(start..(start + chunk).min(candidate.len()));
+                match is_well_formed(&candidate)
+                    .then(|| compare(&candidate))
+                    .flatten()
+                {
+                    Some(smaller) => best = smaller,
+                    None => start += chunk,
+                }
+            }
+            chunk /= 2;
+        }
+        if best.program.len() == len {
+            return best;
+        }
+    }
+}
+
+/// Compare `iterations` random programs of `len` commands, returning the
+/// shrunk counterexample for the first one that differs
+pub fn fuzz(seed: u64, iterations: usize, len: usize) -> Result<(), Mismatch> {
+    let mut rng = Rng::new(seed);
+    for _ in 0..iterations {
+        let program = generate(&mut rng, len);
+        if let Some(mismatch) = compare(&program) {
+            return Err(shrink(mismatch));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generate_well_formed() {
+        let mut rng = Rng::new(7);
+        let (mut calls, mut jumps) = (0, 0);
+        for _ in 0..50 {
+            let program = generate(&mut rng, 40);
+            assert!(program.len() >= 40);
+            assert!(is_well_formed(&program));
+            // `compare` skips programs that don't halt
+            let mut interpreter =
+                Interpreter::new(&[Source::new("Fuzz", program.clone())]).unwrap();
+            for (addr, value) in BASES {
+                interpreter.poke(addr, value);
+            }
+            interpreter.run(MAX_STEPS).unwrap();
+            calls += program
+                .iter()
+                .filter(|cmd| matches!(cmd, Command::Call(..)))
+                .count();
+            jumps += program
+                .iter()
+                .filter(|cmd| matches!(cmd, Command::IfGoto(_)))
+                .count();
+        }
+        assert!(calls > 50 && jumps > 50, "{calls} calls, {jumps} jumps");
+    }
+
+    #[test]
+    fn test_falling_into_function() {
+        let program = crate::parse("push constant 1\nfunction Fuzz.f 0\nreturn").unwrap();
+        assert!(!is_well_formed(&program));
+        assert!(!is_well_formed(&program[1..]));
+        assert!(!is_well_formed(
+            &crate::parse("push constant 1\nreturn").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_comparison_overflow() {
+        // Found by fuzzing, `gt` subtracted operands of opposite sign
+        let program = crate::parse(
+            "push local 4\npush constant 30243\nsub\npush constant 20490\ngt\npop temp 0",
+        )
+        .unwrap();
+        assert_eq!(compare(&program), None);
+    }
+
+    #[test]
+    fn test_differential() {
+        if let Err(mismatch) = fuzz(0x5EED, 1000, 40) {
+            panic!("{mismatch}");
+        }
+    }
+}
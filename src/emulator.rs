@@ -0,0 +1,231 @@
+//! Hack CPU emulator running assembled programs over 32K of RAM
+
+use crate::{
+    assembly::Assembly,
+    hack::{assemble, AssembleError},
+};
+
+pub const RAM_SIZE: usize = 32768;
+
+/// `0;JMP` encoded
+const JMP_ALWAYS: u16 = 0b1110_1010_1000_0111;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum EmulatorError {
+    #[error("program counter {0} is past the end of ROM")]
+    PcOutOfRange(u16),
+    #[error("memory access at {addr} by instruction at ROM[{pc}]")]
+    RamOutOfRange { addr: u16, pc: u16 },
+    #[error("did not halt within {0} steps")]
+    StepLimit(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct Emulator {
+    rom: Vec<u16>,
+    ram: Vec<i16>,
+    pub a: i16,
+    pub d: i16,
+    pub pc: u16,
+}
+
+impl Emulator {
+    /// Load `.hack` machine code words into ROM
+    pub fn new(rom: Vec<u16>) -> Self {
+        Self {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+        }
+    }
+
+    pub fn from_assembly(program: &[Assembly]) -> Result<Self, AssembleError> {
+        Ok(Self::new(assemble(program)?))
+    }
+
+    pub fn ram(&self) -> &[i16] {
+        &self.ram
+    }
+
+    pub fn peek(&self, addr: u16) -> i16 {
+        self.ram[addr as usize]
+    }
+
+    pub fn poke(&mut self, addr: u16, value: i16) {
+        self.ram[addr as usize] = value;
+    }
+
+    /// Reset registers, keeping ROM and RAM
+    pub fn reset(&mut self) {
+        self.a = 0;
+        self.d = 0;
+        self.pc = 0;
+    }
+
+    /// Halted on the `(END) @END 0;JMP` idiom, i.e. an unconditional jump to itself
+    pub fn is_halted(&self) -> bool {
+        let pc = self.pc as usize;
+        self.rom.get(pc) == Some(&self.pc) && self.rom.get(pc + 1) == Some(&JMP_ALWAYS)
+    }
+
+    /// Execute the instruction at PC
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        let instruction = *self
+            .rom
+            .get(self.pc as usize)
+            .ok_or(EmulatorError::PcOutOfRange(self.pc))?;
+
+        // A-instruction
+        if instruction & 0x8000 == 0 {
+            self.a = instruction as i16;
+            self.pc += 1;
+            return Ok(());
+        }
+
+        // C-instruction: 111a cccc ccdd djjj
+        let uses_m = instruction & 0x1000 != 0;
+        let comp = (instruction >> 6) & 0b11_1111;
+        let dest = (instruction >> 3) & 0b111;
+        let jump = instruction & 0b111;
+
+        let addr = self.a as u16;
+        let writes_m = dest & 0b001 != 0;
+        if (uses_m || writes_m) && addr as usize >= RAM_SIZE {
+            return Err(EmulatorError::RamOutOfRange { addr, pc: self.pc });
+        }
+        let y = if uses_m {
+            self.ram[addr as usize]
+        } else {
+            self.a
+        };
+        let out = alu(self.d, y, comp);
+
+        if writes_m {
+            self.ram[addr as usize] = out;
+        }
+        if dest & 0b010 != 0 {
+            self.d = out;
+        }
+        if dest & 0b100 != 0 {
+            self.a = out;
+        }
+
+        let taken = (jump & 0b100 != 0 && out < 0)
+            || (jump & 0b010 != 0 && out == 0)
+            || (jump & 0b001 != 0 && out > 0);
+        self.pc = if taken { addr } else { self.pc + 1 };
+        Ok(())
+    }
+
+    /// Step until halted, returning the number of instructions executed
+    pub fn run(&mut self, max_steps: u64) -> Result<u64, EmulatorError> {
+        let mut steps = 0;
+        while !self.is_halted() {
+            if steps == max_steps {
+                return Err(EmulatorError::StepLimit(max_steps));
+            }
+            self.step()?;
+            steps += 1;
+        }
+        Ok(steps)
+    }
+}
+
+/// The Hack ALU, `comp` is the six control bits zx nx zy ny f no
+fn alu(x: i16, y: i16, comp: u16) -> i16 {
+    let bit = |n: u16| comp & (1 << (5 - n)) != 0;
+    let mut x = if bit(0) { 0 } else { x };
+    if bit(1) {
+        x = !x;
+    }
+    let mut y = if bit(2) { 0 } else { y };
+    if bit(3) {
+        y = !y;
+    }
+    let out = if bit(4) { x.wrapping_add(y) } else { x & y };
+    if bit(5) {
+        !out
+    } else {
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        assembly::{Comp, Dest},
+        parse, translate, Config, Source,
+    };
+
+    fn load_vm(vm: &str) -> Emulator {
+        let program = translate(
+            &[Source::new("Test", parse(vm).unwrap())],
+            &Config::default(),
+        )
+        .unwrap();
+        let mut emulator = Emulator::from_assembly(&program).unwrap();
+        emulator.poke(0, 256);
+        emulator
+    }
+
+    #[test]
+    fn test_alu() {
+        for (comp, d, a, expected) in [
+            (Comp::Zero, 5, 7, 0),
+            (Comp::One, 5, 7, 1),
+            (Comp::MinusOne, 5, 7, -1),
+            (Comp::NotD, 5, 7, !5),
+            (Comp::NegateA, 5, 7, -7),
+            (Comp::Dplus1, 5, 7, 6),
+            (Comp::Aminus1, 5, 7, 6),
+            (Comp::DminusA, 5, 7, -2),
+            (Comp::AminusD, 5, 7, 2),
+            (Comp::DandA, 5, 7, 5),
+            (Comp::DorA, 5, 8, 13),
+            (Comp::DplusA, i16::MAX, 1, i16::MIN),
+        ] {
+            assert_eq!(alu(d, a, comp.code() & 0b11_1111), expected, "{comp}");
+        }
+    }
+
+    #[test]
+    fn test_step() {
+        let program = [
+            Assembly::Address(3),
+            Assembly::assign(Dest::D, Comp::A),
+            Assembly::Address(100),
+            Assembly::assign(Dest::M, Comp::Dplus1),
+        ];
+        let mut emulator = Emulator::from_assembly(&program).unwrap();
+        for _ in 0..4 {
+            emulator.step().unwrap();
+        }
+        assert_eq!(emulator.peek(100), 4);
+        assert_eq!(emulator.step(), Err(EmulatorError::PcOutOfRange(4)));
+    }
+
+    #[test]
+    fn test_stack_arithmetic() {
+        let mut emulator =
+            load_vm("push constant 7\npush constant 8\nadd\npush constant 3\nlt\nneg");
+        emulator.run(1000).unwrap();
+        assert_eq!(emulator.peek(0), 257);
+        assert_eq!(emulator.peek(256), 0);
+
+        let mut emulator =
+            load_vm("push constant 3\npush constant 8\nlt\npush constant 2\npush constant 2\neq");
+        emulator.run(1000).unwrap();
+        assert_eq!(&emulator.ram()[256..258], [-1, -1]);
+    }
+
+    #[test]
+    fn test_loop_halts() {
+        let mut emulator = load_vm("label L\ngoto L");
+        assert_eq!(emulator.run(100), Ok(0));
+        let mut emulator = load_vm("label L\npush constant 1\nif-goto L");
+        assert_eq!(emulator.run(100), Err(EmulatorError::StepLimit(100)));
+    }
+}
@@ -0,0 +1,179 @@
+//! Constant folding and algebraic simplification of VM commands
+//!
+//! Runs before translation. Arithmetic on constants is evaluated with the
+//! same 16-bit two's-complement semantics as the Hack ALU, so
+//! `push constant 2`, `push constant 3`, `add` becomes `push constant 5`.
+//! Only values at the top of the stack built since the last label are
+//! considered, since a jump may arrive with anything on the stack.
+
+use crate::commands::{
+    segment::{Segment, SegmentType},
+    Command,
+};
+
+fn constant(value: i32) -> Command {
+    Command::Push(Segment::new(SegmentType::Constant, value))
+}
+
+/// Shortest commands pushing `value`, `push constant` only takes 0..=32767
+fn push_value(value: i16) -> Vec<Command> {
+    match value {
+        0.. => vec![constant(value as i32)],
+        // !32767 is the one value whose negation does not fit
+        i16::MIN => vec![constant(i16::MAX as i32), Command::Not],
+        _ => vec![constant(-(value as i32)), Command::Negate],
+    }
+}
+
+/// Value pushed by the last commands of `out` and how many commands push it
+fn trailing_constant(out: &[Command]) -> Option<(i16, usize)> {
+    let push = |cmd: &Command| match cmd {
+        Command::Push(Segment {
+            segment: SegmentType::Constant,
+            index,
+        }) => Some(*index as i16),
+        _ => None,
+    };
+    match out {
+        [.., cmd, Command::Negate] => push(cmd).map(|x| (x.wrapping_neg(), 2)),
+        [.., cmd, Command::Not] => push(cmd).map(|x| (!x, 2)),
+        [.., cmd] => push(cmd).map(|x| (x, 1)),
+        [] => None,
+    }
+}
+
+/// Whether `y` on top of any x makes `cmd` leave x unchanged
+fn is_identity(cmd: &Command, y: i16) -> bool {
+    matches!(
+        (cmd, y),
+        (Command::Add | Command::Subtract | Command::Or, 0) | (Command::And, -1)
+    )
+}
+
+/// Fold constant expressions and drop identities, returning the number of
+/// commands eliminated
+pub fn fold(commands: &mut Vec<Command>) -> usize {
+    fold_lines(commands, &mut Vec::new())
+}
+
+/// [`fold`], keeping `lines`, the line of each command if not empty, in step
+///
+/// Folded commands take the line of the operator that was folded.
+pub fn fold_lines(commands: &mut Vec<Command>, lines: &mut Vec<usize>) -> usize {
+    let before = commands.len();
+    let known = lines.len() == before;
+    let mut input_lines = std::mem::take(lines).into_iter();
+    let mut out: Vec<Command> = Vec::with_capacity(before);
+    for cmd in std::mem::take(commands) {
+        let line = input_lines.next().unwrap_or(0);
+        // Commands are only ever removed from or added at the end of `out`
+        let mut replace = |out: &mut Vec<Command>, len: usize, with: Vec<Command>| {
+            out.truncate(out.len() - len);
+            lines.truncate(out.len());
+            lines.extend(std::iter::repeat_n(line, with.len()));
+            out.extend(with);
+        };
+        if let (Some(op), Some((x, len))) = (cmd.unary(), trailing_constant(&out)) {
+            let folded = push_value(op(x));
+            if folded.len() <= len {
+                replace(&mut out, len, folded);
+                continue;
+            }
+        }
+        if matches!(cmd, Command::Negate | Command::Not) && out.last() == Some(&cmd) {
+            // not not x, neg neg x
+            replace(&mut out, 1, Vec::new());
+            continue;
+        }
+        if let (Some(op), Some((y, len_y))) = (cmd.binary(), trailing_constant(&out)) {
+            let rest = &out[..out.len() - len_y];
+            if let Some((x, len_x)) = trailing_constant(rest) {
+                replace(&mut out, len_y + len_x, push_value(op(x, y)));
+                continue;
+            }
+            if is_identity(&cmd, y) {
+                replace(&mut out, len_y, Vec::new());
+                continue;
+            }
+        }
+        replace(&mut out, 0, vec![cmd]);
+    }
+    if !known {
+        lines.clear();
+    }
+    *commands = out;
+    before - commands.len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn folded(source: &str) -> (String, usize) {
+        let mut commands = crate::parse(source).unwrap();
+        let eliminated = fold(&mut commands);
+        let text = commands
+            .iter()
+            .map(|cmd| cmd.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        (text, eliminated)
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        assert_eq!(
+            folded("push constant 2\npush constant 3\nadd"),
+            ("push constant 5".to_string(), 2)
+        );
+        assert_eq!(
+            folded("push constant 0\nnot"),
+            ("push constant 0\nnot".to_string(), 0)
+        );
+        assert_eq!(
+            folded("push constant 2\npush constant 3\nsub"),
+            ("push constant 1\nneg".to_string(), 1)
+        );
+        // Wraps like the Hack ALU
+        assert_eq!(
+            folded("push constant 32767\npush constant 1\nadd"),
+            ("push constant 32767\nnot".to_string(), 1)
+        );
+        assert_eq!(
+            folded("push constant 1\nneg\npush constant 2\nlt\nnot"),
+            ("push constant 0".to_string(), 4)
+        );
+    }
+
+    #[test]
+    fn test_identities() {
+        assert_eq!(
+            folded("push local 0\npush constant 0\nadd\nnot\nnot"),
+            ("push local 0".to_string(), 4)
+        );
+        assert_eq!(
+            folded("push local 0\npush constant 0\nnot\nand"),
+            ("push local 0".to_string(), 3)
+        );
+        // x - y is not y - x
+        assert_eq!(
+            folded("push constant 0\npush local 0\nsub"),
+            ("push constant 0\npush local 0\nsub".to_string(), 0)
+        );
+    }
+
+    #[test]
+    fn test_fold_lines() {
+        let mut commands =
+            crate::parse("push local 0\npush constant 2\npush constant 3\nadd\nadd").unwrap();
+        let mut lines = vec![1, 2, 3, 5, 6];
+        assert_eq!(fold_lines(&mut commands, &mut lines), 2);
+        assert_eq!(lines, [1, 5, 6]);
+    }
+
+    #[test]
+    fn test_labels_stop_folding() {
+        let source = "push constant 1\nlabel L\npush constant 2\nadd";
+        assert_eq!(folded(source), (source.to_string(), 0));
+    }
+}
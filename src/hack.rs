@@ -0,0 +1,262 @@
+//! Assembler from [`Assembly`] to 16-bit Hack machine code
+
+use std::collections::HashMap;
+
+use crate::assembly::{Assembly, Comp, Dest, Jump};
+
+/// First RAM address handed out to variable symbols
+const VARIABLE_BASE: u16 = 16;
+
+/// Words of instruction memory
+pub const ROM_SIZE: usize = 32768;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AssembleError {
+    #[error("label defined twice: {0}")]
+    DuplicateLabel(String),
+    #[error("address {0} does not fit in a 15-bit A-instruction")]
+    AddressOutOfRange(u32),
+    #[error("label {0} is at ROM[{1}], which a 15-bit A-instruction cannot reach")]
+    LabelOutOfRange(String, u16),
+    #[error("program has {0} instructions, more than fit in the {ROM_SIZE}-word ROM")]
+    ProgramTooLarge(usize),
+    #[error("too many variables, {0} would be allocated past RAM[16383]")]
+    TooManyVariables(String),
+}
+
+impl Comp {
+    /// The `a` bit followed by the six `c` bits
+    pub fn code(&self) -> u16 {
+        match self {
+            Comp::Zero => 0b0101010,
+            Comp::One => 0b0111111,
+            Comp::MinusOne => 0b0111010,
+            Comp::D => 0b0001100,
+            Comp::A => 0b0110000,
+            Comp::M => 0b1110000,
+            Comp::NotD => 0b0001101,
+            Comp::NotA => 0b0110001,
+            Comp::NotM => 0b1110001,
+            Comp::NegateD => 0b0001111,
+            Comp::NegateA => 0b0110011,
+            Comp::NegateM => 0b1110011,
+            Comp::Dplus1 => 0b0011111,
+            Comp::Aplus1 => 0b0110111,
+            Comp::Mplus1 => 0b1110111,
+            Comp::Dminus1 => 0b0001110,
+            Comp::Aminus1 => 0b0110010,
+            Comp::Mminus1 => 0b1110010,
+            Comp::DplusA => 0b0000010,
+            Comp::DplusM => 0b1000010,
+            Comp::DminusA => 0b0010011,
+            Comp::DminusM => 0b1010011,
+            Comp::AminusD => 0b0000111,
+            Comp::MminusD => 0b1000111,
+            Comp::DandA => 0b0000000,
+            Comp::DandM => 0b1000000,
+            Comp::DorA => 0b0010101,
+            Comp::DorM => 0b1010101,
+        }
+    }
+}
+
+impl Dest {
+    pub fn code(&self) -> u16 {
+        match self {
+            Dest::None => 0b000,
+            Dest::M => 0b001,
+            Dest::D => 0b010,
+            Dest::DM => 0b011,
+            Dest::A => 0b100,
+            Dest::AM => 0b101,
+            Dest::AD => 0b110,
+            Dest::ADM => 0b111,
+        }
+    }
+}
+
+impl Jump {
+    pub fn code(&self) -> u16 {
+        match self {
+            Jump::None => 0b000,
+            Jump::JGT => 0b001,
+            Jump::JEQ => 0b010,
+            Jump::JGE => 0b011,
+            Jump::JLT => 0b100,
+            Jump::JNE => 0b101,
+            Jump::JLE => 0b110,
+            Jump::JMP => 0b111,
+        }
+    }
+}
+
+/// Symbols every Hack program can use without defining them
+fn predefined_symbols() -> HashMap<String, u16> {
+    let mut symbols = HashMap::from(
+        [
+            ("SP", 0),
+            ("LCL", 1),
+            ("ARG", 2),
+            ("THIS", 3),
+            ("THAT", 4),
+            ("SCREEN", 16384),
+            ("KBD", 24576),
+        ]
+        .map(|(name, addr)| (name.to_string(), addr)),
+    );
+    for r in 0..16 {
+        symbols.insert(format!("R{r}"), r);
+    }
+    symbols
+}
+
+/// Resolve labels and variables and encode every instruction
+///
+/// Labels and comments produce no machine code, variables are allocated
+/// from RAM[16] in order of first use
+pub fn assemble(program: &[Assembly]) -> Result<Vec<u16>, AssembleError> {
+    let mut symbols = predefined_symbols();
+
+    // First pass: label ROM addresses
+    let mut rom = 0u16;
+    for asm in program {
+        match asm {
+            Assembly::Label(label) => {
+                if symbols.insert(label.to_string(), rom).is_some() {
+                    return Err(AssembleError::DuplicateLabel(label.to_string()));
+                }
+            }
+            Assembly::Comment(_) => {}
+            _ if rom as usize == ROM_SIZE => {
+                let count = program.iter().filter(|asm| asm.is_instruction()).count();
+                return Err(AssembleError::ProgramTooLarge(count));
+            }
+            _ => rom += 1,
+        }
+    }
+
+    // Second pass: encode, allocating variables as they are seen
+    let mut next_variable = VARIABLE_BASE;
+    let mut words = Vec::with_capacity(rom as usize);
+    for asm in program {
+        match asm {
+            Assembly::Label(_) | Assembly::Comment(_) => {}
+            Assembly::Address(addr) => {
+                if *addr > 0x7FFF {
+                    return Err(AssembleError::AddressOutOfRange(*addr));
+                }
+                words.push(*addr as u16);
+            }
+            Assembly::VariableSymbol(symbol) => {
+                let addr = match symbols.get(symbol.as_ref()) {
+                    // Only a label just past a full ROM can be this high
+                    Some(&addr) if addr > 0x7FFF => {
+                        return Err(AssembleError::LabelOutOfRange(symbol.to_string(), addr))
+                    }
+                    Some(addr) => *addr,
+                    None => {
+                        if next_variable >= 16384 {
+                            return Err(AssembleError::TooManyVariables(symbol.to_string()));
+                        }
+                        symbols.insert(symbol.to_string(), next_variable);
+                        next_variable += 1;
+                        next_variable - 1
+                    }
+                };
+                words.push(addr);
+            }
+            Assembly::Command { dest, comp, jump } => {
+                words.push(0b111 << 13 | comp.code() << 6 | dest.code() << 3 | jump.code());
+            }
+        }
+    }
+    Ok(words)
+}
+
+/// `.hack` file contents, one 16-character binary word per line
+pub fn render_hack(words: &[u16]) -> String {
+    words.iter().map(|word| format!("{word:016b}\n")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let program = [
+            Assembly::comment("D = 2"),
+            Assembly::Address(2),
+            Assembly::assign(Dest::D, Comp::A),
+            Assembly::label("LOOP"),
+            Assembly::addr_sym("counter"),
+            Assembly::assign(Dest::M, Comp::D),
+            Assembly::addr_sym("other"),
+            Assembly::addr_sym("counter"),
+            Assembly::addr_sym("LOOP"),
+            Assembly::Command {
+                dest: Dest::None,
+                comp: Comp::D,
+                jump: Jump::JNE,
+            },
+            Assembly::sp(),
+        ];
+        let hack = render_hack(&assemble(&program).unwrap());
+        assert_eq!(
+            hack.lines().collect::<Vec<_>>(),
+            [
+                "0000000000000010",
+                "1110110000010000",
+                "0000000000010000",
+                "1110001100001000",
+                "0000000000010001",
+                "0000000000010000",
+                "0000000000000010",
+                "1110001100000101",
+                "0000000000000000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comp_codes_unique() {
+        let codes = Comp::ALL
+            .iter()
+            .map(Comp::code)
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(codes.len(), 28);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            assemble(&[Assembly::label("A"), Assembly::label("A")]),
+            Err(AssembleError::DuplicateLabel(_))
+        ));
+        assert!(matches!(
+            assemble(&[Assembly::Address(32768)]),
+            Err(AssembleError::AddressOutOfRange(32768))
+        ));
+    }
+
+    #[test]
+    fn test_rom_limits() {
+        let nop = Assembly::assign(Dest::D, Comp::Zero);
+        // A full ROM, with a label just past its end
+        let mut program = vec![Assembly::addr_sym("L")];
+        program.extend(std::iter::repeat_n(nop.clone(), ROM_SIZE - 1));
+        program.push(Assembly::label("L"));
+        assert!(matches!(
+            assemble(&program),
+            Err(AssembleError::LabelOutOfRange(_, 32768))
+        ));
+
+        program.pop();
+        assert_eq!(assemble(&program).unwrap().len(), ROM_SIZE);
+        program.extend(std::iter::repeat_n(nop, 40000));
+        assert!(matches!(
+            assemble(&program),
+            Err(AssembleError::ProgramTooLarge(72768))
+        ));
+    }
+}
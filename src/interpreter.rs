@@ -0,0 +1,434 @@
+//! Reference VM interpreter executing [`Command`]s directly
+//!
+//! Memory uses the standard Hack layout (SP, LCL, ARG, THIS, THAT in RAM[0..5],
+//! temp in RAM[5..13], statics from RAM[16]) so its RAM can be compared word
+//! for word with an [`Emulator`](crate::emulator::Emulator) running the
+//! translated program. Statics are allocated in order of first appearance,
+//! as the assembler does. Saved return addresses are command indices rather
+//! than ROM addresses, so call frames are the one place the two differ.
+
+use std::collections::HashMap;
+
+use crate::{
+    commands::{
+        segment::{Segment, SegmentType, LATT},
+        Command,
+    },
+    emulator::RAM_SIZE,
+    program::{validate, Source, ValidationError},
+};
+
+const SP: u16 = 0;
+const LCL: u16 = 1;
+const ARG: u16 = 2;
+const THIS: u16 = 3;
+const THAT: u16 = 4;
+const TEMP: u16 = 5;
+const STATIC: u16 = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum InterpreterError {
+    #[error(transparent)]
+    Invalid(#[from] ValidationError),
+    #[error("unknown label {0}")]
+    UnknownLabel(String),
+    #[error("unknown function {0}")]
+    UnknownFunction(String),
+    #[error("function defined twice: {0}")]
+    DuplicateFunction(String),
+    #[error("label defined twice: {0}")]
+    DuplicateLabel(String),
+    #[error("memory access at {addr} by command {pc}")]
+    RamOutOfRange { addr: i16, pc: usize },
+    #[error("did not halt within {0} steps")]
+    StepLimit(u64),
+}
+
+/// Where a push reads from or a pop writes to
+#[derive(Debug, Clone, Copy)]
+enum Location {
+    Constant(i16),
+    /// RAM[RAM[base] + index]
+    Indirect(u16, i16),
+    /// RAM[addr]
+    Direct(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Push(Location),
+    Pop(Location),
+    Unary(fn(i16) -> i16),
+    Binary(fn(i16, i16) -> i16),
+    Goto(usize),
+    IfGoto(usize),
+    Function(u32),
+    Call(usize, u32),
+    Return,
+    Nop,
+}
+
+#[derive(Debug, Clone)]
+pub struct Interpreter {
+    ops: Vec<Op>,
+    functions: HashMap<String, usize>,
+    ram: Vec<i16>,
+    pub pc: usize,
+}
+
+impl Interpreter {
+    /// Load `sources` with RAM zeroed except for SP = 256
+    pub fn new(sources: &[Source]) -> Result<Self, InterpreterError> {
+        // Reuse the translator's checks so both reject the same programs
+        validate(sources)?;
+
+        // First pass: label and function addresses, scoped like `Babel`
+        let mut labels = HashMap::new();
+        let mut functions = HashMap::new();
+        let mut scopes = Vec::new();
+        for source in sources {
+            let mut function: Option<&str> = None;
+            for cmd in &source.commands {
+                if let Command::Function(name, _) = cmd {
+                    function = Some(name);
+                    if functions.insert(name.clone(), scopes.len()).is_some() {
+                        return Err(InterpreterError::DuplicateFunction(name.clone()));
+                    }
+                }
+                let scope = |label: &str| match function {
+                    Some(f) => format!("{f}${label}"),
+                    None => label.to_string(),
+                };
+                if let Command::Label(label) = cmd {
+                    let scoped = scope(label);
+                    if labels.insert(scoped.clone(), scopes.len()).is_some() {
+                        return Err(InterpreterError::DuplicateLabel(scoped));
+                    }
+                }
+                scopes.push(function.map(str::to_string));
+            }
+        }
+
+        // Second pass: resolve every command
+        let mut statics = HashMap::new();
+        let mut ops = Vec::with_capacity(scopes.len());
+        let commands = sources
+            .iter()
+            .enumerate()
+            .flat_map(|(file, source)| source.commands.iter().map(move |cmd| (file, cmd)));
+        for ((file, cmd), scope) in commands.zip(&scopes) {
+            let label = |label: &str| {
+                let scoped = match scope {
+                    Some(f) => format!("{f}${label}"),
+                    None => label.to_string(),
+                };
+                labels
+                    .get(&scoped)
+                    .copied()
+                    .ok_or(InterpreterError::UnknownLabel(scoped))
+            };
+            let mut location = |segment: &Segment| {
+                let index = segment.index() as i16;
+                match segment.segment() {
+                    SegmentType::Constant => Location::Constant(index),
+                    SegmentType::LATT(latt) => {
+                        let base = match latt {
+                            LATT::Local => LCL,
+                            LATT::Argument => ARG,
+                            LATT::This => THIS,
+                            LATT::That => THAT,
+                        };
+                        Location::Indirect(base, index)
+                    }
+                    SegmentType::Pointer => Location::Direct(THIS + index as u16),
+                    SegmentType::Temp => Location::Direct(TEMP + index as u16),
+                    SegmentType::Static => {
+                        let next = STATIC + statics.len() as u16;
+                        Location::Direct(*statics.entry((file, index)).or_insert(next))
+                    }
+                }
+            };
+            let op = match cmd {
+                Command::Push(segment) => Op::Push(location(segment)),
+                Command::Pop(segment) => Op::Pop(location(segment)),
+                Command::Label(_) => Op::Nop,
+                Command::Goto(l) => Op::Goto(label(l)?),
+                Command::IfGoto(l) => Op::IfGoto(label(l)?),
+                Command::Function(_, n_locals) => Op::Function(*n_locals),
+                Command::Call(name, n_args) => {
+                    let target = functions
+                        .get(name)
+                        .ok_or_else(|| InterpreterError::UnknownFunction(name.clone()))?;
+                    Op::Call(*target, *n_args)
+                }
+                Command::Return => Op::Return,
+                Command::Negate | Command::Not => Op::Unary(cmd.unary().expect("unary")),
+                Command::Add
+                | Command::Subtract
+                | Command::And
+                | Command::Or
+                | Command::Equal
+                | Command::GreaterThan
+                | Command::LessThan => Op::Binary(cmd.binary().expect("binary")),
+            };
+            ops.push(op);
+        }
+
+        let mut ram = vec![0; RAM_SIZE];
+        ram[SP as usize] = 256;
+        Ok(Self {
+            ops,
+            functions,
+            ram,
+            pc: 0,
+        })
+    }
+
+    pub fn ram(&self) -> &[i16] {
+        &self.ram
+    }
+
+    pub fn peek(&self, addr: u16) -> i16 {
+        self.ram[addr as usize]
+    }
+
+    pub fn poke(&mut self, addr: u16, value: i16) {
+        self.ram[addr as usize] = value;
+    }
+
+    /// Set SP to 256 and call `Sys.init`, like [`Babel::bootstrap`](crate::babel::Babel::bootstrap)
+    pub fn bootstrap(&mut self, sentinels: bool) -> Result<(), InterpreterError> {
+        self.ram[SP as usize] = 256;
+        if sentinels {
+            for (value, base) in [(-1, LCL), (-2, ARG), (-3, THIS), (-4, THAT)] {
+                self.ram[base as usize] = value;
+            }
+        }
+        let target = *self
+            .functions
+            .get("Sys.init")
+            .ok_or_else(|| InterpreterError::UnknownFunction("Sys.init".to_string()))?;
+        // Sys.init returns past the end of the program, which halts
+        self.pc = self.ops.len();
+        self.call(target, 0)
+    }
+
+    /// Halted past the end of the program, or on a `goto` to itself
+    pub fn is_halted(&self) -> bool {
+        match self.ops.get(self.pc) {
+            None => true,
+            Some(Op::Goto(target)) => self
+                .ops
+                .get(*target..self.pc)
+                .is_some_and(|ops| ops.iter().all(|op| matches!(op, Op::Nop))),
+            Some(_) => false,
+        }
+    }
+
+    fn addr(&self, addr: i16) -> Result<usize, InterpreterError> {
+        if addr < 0 {
+            Err(InterpreterError::RamOutOfRange { addr, pc: self.pc })
+        } else {
+            Ok(addr as usize)
+        }
+    }
+
+    fn read(&self, addr: i16) -> Result<i16, InterpreterError> {
+        Ok(self.ram[self.addr(addr)?])
+    }
+
+    fn write(&mut self, addr: i16, value: i16) -> Result<(), InterpreterError> {
+        let addr = self.addr(addr)?;
+        self.ram[addr] = value;
+        Ok(())
+    }
+
+    fn push(&mut self, value: i16) -> Result<(), InterpreterError> {
+        let sp = self.ram[SP as usize];
+        self.write(sp, value)?;
+        self.ram[SP as usize] = sp.wrapping_add(1);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i16, InterpreterError> {
+        let sp = self.ram[SP as usize].wrapping_sub(1);
+        self.ram[SP as usize] = sp;
+        self.read(sp)
+    }
+
+    fn location_addr(&self, location: Location) -> i16 {
+        match location {
+            Location::Constant(_) => unreachable!("constants have no address"),
+            Location::Indirect(base, index) => self.ram[base as usize].wrapping_add(index),
+            Location::Direct(addr) => addr as i16,
+        }
+    }
+
+    fn call(&mut self, target: usize, n_args: u32) -> Result<(), InterpreterError> {
+        self.push(self.pc as i16)?;
+        for base in [LCL, ARG, THIS, THAT] {
+            self.push(self.ram[base as usize])?;
+        }
+        let sp = self.ram[SP as usize];
+        self.ram[ARG as usize] = sp.wrapping_sub(5 + n_args as i16);
+        self.ram[LCL as usize] = sp;
+        self.pc = target;
+        Ok(())
+    }
+
+    /// Execute the command at PC
+    pub fn step(&mut self) -> Result<(), InterpreterError> {
+        let Some(op) = self.ops.get(self.pc).copied() else {
+            return Ok(());
+        };
+        let mut next = self.pc + 1;
+        match op {
+            Op::Push(Location::Constant(value)) => self.push(value)?,
+            Op::Push(location) => {
+                let value = self.read(self.location_addr(location))?;
+                self.push(value)?;
+            }
+            Op::Pop(location) => {
+                let addr = self.location_addr(location);
+                let value = self.pop()?;
+                self.write(addr, value)?;
+            }
+            Op::Unary(f) => {
+                let x = self.pop()?;
+                self.push(f(x))?;
+            }
+            Op::Binary(f) => {
+                let y = self.pop()?;
+                let x = self.pop()?;
+                self.push(f(x, y))?;
+            }
+            Op::Goto(target) => next = target,
+            Op::IfGoto(target) => {
+                if self.pop()? != 0 {
+                    next = target;
+                }
+            }
+            Op::Function(n_locals) => {
+                for _ in 0..n_locals {
+                    self.push(0)?;
+                }
+            }
+            Op::Call(target, n_args) => {
+                self.pc = next;
+                return self.call(target, n_args);
+            }
+            Op::Return => {
+                let frame = self.ram[LCL as usize];
+                let return_address = self.read(frame.wrapping_sub(5))?;
+                let value = self.pop()?;
+                let arg = self.ram[ARG as usize];
+                self.write(arg, value)?;
+                self.ram[SP as usize] = arg.wrapping_add(1);
+                for (offset, base) in [(1, THAT), (2, THIS), (3, ARG), (4, LCL)] {
+                    self.ram[base as usize] = self.read(frame.wrapping_sub(offset))?;
+                }
+                next = return_address as u16 as usize;
+            }
+            Op::Nop => {}
+        }
+        self.pc = next;
+        Ok(())
+    }
+
+    /// Step until halted, returning the number of commands executed
+    pub fn run(&mut self, max_steps: u64) -> Result<u64, InterpreterError> {
+        let mut steps = 0;
+        while !self.is_halted() {
+            if steps == max_steps {
+                return Err(InterpreterError::StepLimit(max_steps));
+            }
+            self.step()?;
+            steps += 1;
+        }
+        Ok(steps)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse;
+
+    fn interpreter(files: &[(&str, &str)]) -> Interpreter {
+        let sources = files
+            .iter()
+            .map(|(name, vm)| Source::new(*name, parse(vm).unwrap()))
+            .collect::<Vec<_>>();
+        Interpreter::new(&sources).unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let mut vm = interpreter(&[(
+            "Main",
+            "push constant 7\npush constant 8\nsub\npush constant 3\nlt\n\
+             push constant 32767\npush constant 1\nadd",
+        )]);
+        vm.run(100).unwrap();
+        assert_eq!(vm.peek(SP), 258);
+        assert_eq!(&vm.ram()[256..258], [-1, i16::MIN]);
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut vm = interpreter(&[
+            ("A", "push constant 5\npop static 3\npush constant 6\npop static 0"),
+            ("B", "push constant 7\npop static 3\npush constant 3010\npop pointer 1\npush constant 9\npop that 2\npush constant 1\npop temp 7"),
+        ]);
+        vm.run(100).unwrap();
+        // Statics are allocated in order of first use
+        assert_eq!(&vm.ram()[16..19], [5, 6, 7]);
+        assert_eq!(vm.peek(THAT), 3010);
+        assert_eq!(vm.peek(3012), 9);
+        assert_eq!(vm.peek(12), 1);
+    }
+
+    #[test]
+    fn test_function_calls() {
+        let mut vm = interpreter(&[(
+            "Sys",
+            "function Sys.init 0\npush constant 4\ncall Sys.double 1\nlabel END\ngoto END\n\
+                 function Sys.double 1\npush argument 0\npush argument 0\nadd\npop local 0\n\
+                 push local 0\nreturn",
+        )]);
+        vm.bootstrap(false).unwrap();
+        vm.run(100).unwrap();
+        assert_eq!(vm.peek(SP), 262);
+        assert_eq!(vm.peek(261), 8);
+    }
+
+    #[test]
+    fn test_loop() {
+        let mut vm = interpreter(&[(
+            "Main",
+            "push constant 0\npop temp 0\npush constant 5\npop temp 1\nlabel LOOP\n\
+             push temp 0\npush temp 1\nadd\npop temp 0\npush temp 1\npush constant 1\nsub\n\
+             pop temp 1\npush temp 1\nif-goto LOOP",
+        )]);
+        vm.run(1000).unwrap();
+        assert_eq!(vm.peek(TEMP), 15);
+        let mut vm = interpreter(&[("Main", "goto SKIP\npush constant 1\nlabel SKIP")]);
+        vm.run(10).unwrap();
+        assert_eq!(vm.peek(SP), 256);
+        assert!(matches!(
+            Interpreter::new(&[Source::new("Main", parse("goto NOWHERE").unwrap())]),
+            Err(InterpreterError::UnknownLabel(_))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let load = |vm: &str| Interpreter::new(&[Source::new("Main", parse(vm).unwrap())]);
+        assert!(matches!(
+            load("function Main.f 0\nlabel L\nlabel L"),
+            Err(InterpreterError::DuplicateLabel(label)) if label == "Main.f$L"
+        ));
+        // The same name in another scope is a different label
+        assert!(load("label L\nfunction Main.f 0\nlabel L\nfunction Main.g 0\nlabel L").is_ok());
+    }
+}
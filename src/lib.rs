@@ -0,0 +1,42 @@
+//! Translator from the nand2tetris VM language to Hack assembly
+//!
+//! ```
+//! use vm_translator_rs::{parse, render, translate, Config, Source};
+//!
+//! let commands = parse("push constant 7\npush constant 8\nadd").unwrap();
+//! let program = translate(&[Source::new("Main", commands)], &Config::default()).unwrap();
+//! assert!(render(&program).starts_with("// push constant 7\n@7\n"));
+//! ```
+pub mod assembly;
+pub mod babel;
+pub mod commands;
+pub mod difftest;
+pub mod emulator;
+pub mod fold;
+pub mod hack;
+pub mod interpreter;
+pub mod peephole;
+pub mod program;
+pub mod source_map;
+pub mod tst;
+pub mod utils;
+
+pub use assembly::{parse_asm, AsmParseError, AsmSourceError, Assembly};
+pub use babel::{Babel, Translation};
+pub use commands::{
+    source::{parse_numbered, parse_source, SourceError},
+    Command, ParseError,
+};
+pub use emulator::{Emulator, EmulatorError};
+pub use fold::{fold, fold_lines};
+pub use hack::{assemble, render_hack, AssembleError};
+pub use interpreter::{Interpreter, InterpreterError};
+pub use peephole::optimize;
+pub use program::{render, render_with, translate, Comments, Config, Source, ValidationError};
+pub use source_map::{translate_mapped, SourceMap};
+pub use tst::{compare, ScriptOutput, TestScript, TstError};
+
+/// Parse VM source text, see [`parse_source`] to attach a file name to errors
+pub fn parse(source: &str) -> Result<Vec<Command>, SourceError> {
+    parse_source("<input>", source)
+}
@@ -1,58 +1,1003 @@
 #![allow(non_snake_case)]
-mod assembly;
-mod babel;
-mod commands;
-mod utils;
-
 use std::{
     env,
-    fs::File,
-    io::{BufRead, BufReader},
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
     path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
 };
 
-use babel::Translation;
-
-use crate::{babel::Babel, commands::Command};
+use eyre::WrapErr;
+use vm_translator_rs::{
+    assemble, compare, fold_lines, optimize, parse_asm, parse_numbered, program::validate,
+    render_hack, render_with, translate_mapped, Assembly, Comments, Config, Emulator, Source,
+    SourceMap, TestScript,
+};
 
-fn run<P: AsRef<Path>>(path: P) -> eyre::Result<()> {
-    let basename = path
-        .as_ref()
-        .file_stem()
+fn file_stem(path: &Path) -> eyre::Result<&str> {
+    path.file_stem()
         .ok_or(eyre::eyre!("Not a file"))?
         .to_str()
-        .ok_or(eyre::eyre!("Invalid filename bytes"))?;
-    let mut babel = Babel::empty(basename);
-    let reader = BufReader::new(File::open(path)?);
-    for line in reader.lines() {
-        let line = line?;
-        if let Some(line) = utils::remove_whitespace_comments(&line) {
-            let cmd = line.parse::<Command>()?;
-            let asm = babel.translate(&cmd);
-            for instruction in asm {
-                println!("{}", instruction);
+        .ok_or(eyre::eyre!("Invalid filename bytes"))
+}
+
+/// All `.vm` files in a directory, sorted so output is deterministic
+fn vm_files(dir: &Path) -> eyre::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "vm") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    if files.is_empty() {
+        return Err(eyre::eyre!("No .vm files in {}", dir.display()));
+    }
+    Ok(files)
+}
+
+const USAGE: &str = "\
+Usage: VMTranslator [COMMAND] [OPTIONS] <PATH>...
+
+Translate nand2tetris VM code into Hack assembly. Each PATH is a .vm file, a
+directory of .vm files, or - for standard input, all translated together into
+one program.
+
+Commands:
+  translate  Write the translation of PATH (the default command)
+  assemble   Assemble the .asm file PATH into .hack machine code
+  run        Execute PATH on the Hack emulator, or run the .tst script PATH
+  check      Parse and validate PATH without writing anything
+  help       Print this message
+
+Options:
+  -o, --output <FILE>    Output file, by default Foo.asm next to Foo.vm, or
+                         Foo/Foo.asm for a directory Foo. Standard output if
+                         FILE is -, or by default for several inputs or stdin
+      --name <NAME>      Prefix of static symbols of a single input, by
+                         default its file stem, or Stdin [translate, run, check]
+      --emit <FORMAT>    asm, hack or json [translate]
+      --comments <MODE>  none, source or verbose, for asm output [translate]
+      --source-map       Also write Foo.map.json mapping ROM addresses to VM
+                         commands [translate]
+      --fold             Evaluate arithmetic on constants before translating
+                         [translate, run]
+      --cache-top        Keep the top of the stack in D between commands
+                         [translate, run]
+      --peephole         Remove redundant instructions after translating
+                         [translate, run]
+      --optimize         All of --fold, --cache-top and --peephole
+                         [translate, run]
+      --compact          Share one routine per comparison, call and return
+                         [translate, run]
+      --bootstrap        Set SP and call Sys.init before the program
+                         [translate, run]
+      --no-bootstrap     Never bootstrap; by default only directories and
+                         several files defining Sys.init are bootstrapped
+                         [translate, run]
+      --sentinels        Bootstrap LCL/ARG/THIS/THAT to -1..-4 [translate, run]
+      --steps <N>        Give up if not halted after N instructions, default
+                         1000000 [run]
+  -h, --help             Print this message
+
+Exit status is 0 on success, 1 if translation, assembly or a run fails, and 2
+for usage errors.
+";
+
+/// A command line that cannot be run
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct UsageError(String);
+
+fn usage<S: Into<String>>(message: S) -> UsageError {
+    UsageError(message.into())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Subcommand {
+    Translate,
+    Assemble,
+    Run,
+    Check,
+}
+
+impl Subcommand {
+    fn name(self) -> &'static str {
+        match self {
+            Subcommand::Translate => "translate",
+            Subcommand::Assemble => "assemble",
+            Subcommand::Run => "run",
+            Subcommand::Check => "check",
+        }
+    }
+}
+
+/// Output format
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    Asm,
+    Hack,
+    /// Instructions by ROM address with the source map
+    Json,
+}
+
+impl Emit {
+    fn extension(self) -> &'static str {
+        match self {
+            Emit::Asm => "asm",
+            Emit::Hack => "hack",
+            Emit::Json => "json",
+        }
+    }
+}
+
+impl FromStr for Emit {
+    type Err = UsageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asm" => Ok(Emit::Asm),
+            "hack" => Ok(Emit::Hack),
+            "json" => Ok(Emit::Json),
+            _ => Err(usage(format!(
+                "unknown output format {s}, expected asm, hack or json"
+            ))),
+        }
+    }
+}
+
+fn parse_comments(s: &str) -> Result<Comments, UsageError> {
+    match s {
+        "none" => Ok(Comments::None),
+        "source" => Ok(Comments::Source),
+        "verbose" => Ok(Comments::Verbose),
+        _ => Err(usage(format!(
+            "unknown comment mode {s}, expected none, source or verbose"
+        ))),
+    }
+}
+
+/// Command line options
+struct Options {
+    command: Subcommand,
+    /// `.vm` files and directories translated together, `-` for stdin
+    paths: Vec<PathBuf>,
+    /// Defaults to `Foo.asm` next to `Foo.vm`, or `Foo/Foo.asm` for a
+    /// directory, `-` and several inputs default to stdout
+    output: Option<PathBuf>,
+    /// Static prefix of the only input, instead of its file stem
+    name: Option<String>,
+    emit: Emit,
+    /// `None` bootstraps only directories and file lists that define `Sys.init`
+    bootstrap: Option<bool>,
+    /// Initialise LCL/ARG/THIS/THAT to the test-harness sentinel values
+    sentinels: bool,
+    /// Fold constant expressions before translating
+    fold: bool,
+    /// Keep the stack top in D between commands
+    cache_top: bool,
+    /// Run the peephole optimiser over the translation
+    peephole: bool,
+    /// Jump to shared routines for comparisons, calls and returns
+    compact: bool,
+    /// Also write `Foo.map.json` mapping ROM addresses to VM commands
+    source_map: bool,
+    /// Comments in `.asm` output
+    comments: Comments,
+    /// Instructions the `run` command executes before giving up
+    steps: u64,
+}
+
+impl Options {
+    fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            command: Subcommand::Translate,
+            paths: vec![path.into()],
+            output: None,
+            name: None,
+            emit: Emit::Asm,
+            bootstrap: None,
+            sentinels: false,
+            fold: false,
+            cache_top: false,
+            peephole: false,
+            compact: false,
+            source_map: false,
+            comments: Comments::default(),
+            steps: 1_000_000,
+        }
+    }
+
+    /// Parse arguments after the program name, `None` if help was requested
+    fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Option<Self>, UsageError> {
+        let mut args = args.peekable();
+        let command = match args.peek().map(String::as_str) {
+            Some("translate") => Some(Subcommand::Translate),
+            Some("assemble") => Some(Subcommand::Assemble),
+            Some("run") => Some(Subcommand::Run),
+            Some("check") => Some(Subcommand::Check),
+            Some("help") => return Ok(None),
+            // Plain `VMTranslator Foo.vm`, as the course's tools invoke it
+            _ => None,
+        };
+        if command.is_some() {
+            args.next();
+        }
+        let mut options = Self {
+            command: command.unwrap_or(Subcommand::Translate),
+            ..Self::new(PathBuf::new())
+        };
+        let help = options.parse_options(args)?;
+        Ok((!help).then_some(options))
+    }
+
+    /// Parse options and the path into `self`, returning whether help was requested
+    fn parse_options<I: Iterator<Item = String>>(
+        &mut self,
+        mut args: I,
+    ) -> Result<bool, UsageError> {
+        let mut paths: Vec<PathBuf> = Vec::new();
+        while let Some(arg) = args.next() {
+            let command = self.command;
+            let only = |commands: &[Subcommand]| {
+                if commands.contains(&command) {
+                    Ok(())
+                } else {
+                    Err(usage(format!("{arg} does not apply to {}", command.name())))
+                }
+            };
+            let mut value = |expected: &str| {
+                args.next()
+                    .ok_or_else(|| usage(format!("{arg} requires {expected}")))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(true),
+                "-o" | "--output" => {
+                    only(&[Subcommand::Translate, Subcommand::Assemble])?;
+                    self.output = Some(PathBuf::from(value("a path")?));
+                }
+                "--name" => {
+                    only(&[Subcommand::Translate, Subcommand::Run, Subcommand::Check])?;
+                    self.name = Some(value("a name")?);
+                }
+                "--emit" => {
+                    only(&[Subcommand::Translate])?;
+                    self.emit = value("asm, hack or json")?.parse()?;
+                }
+                "--comments" => {
+                    only(&[Subcommand::Translate])?;
+                    self.comments = parse_comments(&value("none, source or verbose")?)?;
+                }
+                "--source-map" => {
+                    only(&[Subcommand::Translate])?;
+                    self.source_map = true;
+                }
+                "--bootstrap" | "--no-bootstrap" | "--sentinels" | "--fold" | "--cache-top"
+                | "--peephole" | "--optimize" | "--compact" => {
+                    only(&[Subcommand::Translate, Subcommand::Run])?;
+                    match arg.as_str() {
+                        "--bootstrap" => self.bootstrap = Some(true),
+                        "--no-bootstrap" => self.bootstrap = Some(false),
+                        "--sentinels" => self.sentinels = true,
+                        "--fold" => self.fold = true,
+                        "--cache-top" => self.cache_top = true,
+                        "--peephole" => self.peephole = true,
+                        "--optimize" => {
+                            self.fold = true;
+                            self.cache_top = true;
+                            self.peephole = true;
+                        }
+                        _ => self.compact = true,
+                    }
+                }
+                "--steps" => {
+                    only(&[Subcommand::Run])?;
+                    let steps = value("a number")?;
+                    self.steps = steps
+                        .parse()
+                        .map_err(|_| usage(format!("{arg} requires a number, got {steps}")))?;
+                }
+                flag if flag.starts_with('-') && flag != STDIN => {
+                    return Err(usage(format!("unknown option {flag}")))
+                }
+                _ if arg == STDIN && paths.iter().any(|path| is_stdin(path)) => {
+                    return Err(usage("- can only be given once"))
+                }
+                _ => paths.push(PathBuf::from(arg)),
+            }
+        }
+        if paths.is_empty() {
+            return Err(usage("missing input path"));
+        }
+        if paths.len() > 1 && self.command == Subcommand::Assemble {
+            return Err(usage(format!("unexpected argument {}", paths[1].display())));
+        }
+        if paths.len() > 1 && self.name.is_some() {
+            return Err(usage("--name requires a single input"));
+        }
+        self.paths = paths;
+        Ok(false)
+    }
+
+    /// Where to write, `None` for stdout
+    fn output_path(&self) -> eyre::Result<Option<PathBuf>> {
+        if let Some(output) = &self.output {
+            return Ok((!is_stdin(output)).then(|| output.clone()));
+        }
+        let extension = match self.command {
+            Subcommand::Assemble => "hack",
+            _ => self.emit.extension(),
+        };
+        match self.paths.as_slice() {
+            [path] if is_stdin(path) => Ok(None),
+            // Directory Foo/ is translated into Foo/Foo.asm
+            [path] if path.is_dir() => {
+                Ok(Some(path.join(format!("{}.{extension}", file_stem(path)?))))
             }
+            [path] => Ok(Some(path.with_extension(extension))),
+            // Several inputs have no one place for the output
+            _ => Ok(None),
         }
     }
-    for instruction in Translation::finish() {
-        println!("{}", instruction);
+
+    /// Whether the inputs are meant as a whole program rather than one file
+    fn is_program(&self) -> bool {
+        self.paths.len() > 1 || self.paths.iter().any(|path| path.is_dir())
+    }
+
+    /// The inputs for messages
+    fn describe(&self) -> String {
+        self.paths
+            .iter()
+            .map(|path| {
+                if is_stdin(path) {
+                    "<stdin>".to_string()
+                } else {
+                    path.display().to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The path standing for stdin
+const STDIN: &str = "-";
+
+fn is_stdin(path: &Path) -> bool {
+    path == Path::new(STDIN)
+}
+
+fn read(path: &Path) -> eyre::Result<String> {
+    if is_stdin(path) {
+        let mut source = String::new();
+        io::stdin()
+            .read_to_string(&mut source)
+            .wrap_err("cannot read stdin")?;
+        return Ok(source);
+    }
+    fs::read_to_string(path).wrap_err_with(|| format!("cannot read {}", path.display()))
+}
+
+/// Parse a `.vm` file, or stdin as `Stdin.vm`
+fn parse_file(path: &Path) -> eyre::Result<Source> {
+    let source = read(path)?;
+    let (file, name) = if is_stdin(path) {
+        (Path::new("<stdin>"), "Stdin")
+    } else {
+        (path, file_stem(path)?)
+    };
+    let (lines, commands) = parse_numbered(file, &source)?.into_iter().unzip();
+    Ok(Source::new(name, commands)
+        .with_lines(lines)
+        .with_text(source))
+}
+
+/// Parse every input file and every `.vm` file in an input directory
+fn parse_inputs(options: &Options) -> eyre::Result<Vec<Source>> {
+    let mut sources = Vec::new();
+    for path in &options.paths {
+        if path.is_dir() {
+            for file in vm_files(path)? {
+                sources.push(parse_file(&file)?);
+            }
+        } else {
+            sources.push(parse_file(path)?);
+        }
+    }
+    if let Some(name) = &options.name {
+        match sources.as_mut_slice() {
+            [source] => source.name = name.clone(),
+            _ => {
+                return Err(eyre::eyre!(
+                    "--name requires a single .vm file, {} has {}",
+                    options.describe(),
+                    sources.len()
+                ))
+            }
+        }
+    }
+    // Statics are prefixed with the file name, so same-named files would share them
+    for (i, source) in sources.iter().enumerate() {
+        if sources[..i].iter().any(|other| other.name == source.name) {
+            return Err(eyre::eyre!(
+                "more than one input is named {}.vm",
+                source.name
+            ));
+        }
+    }
+    Ok(sources)
+}
+
+/// Translate the `.vm` files of every input into one program
+fn translate_path(options: &Options) -> eyre::Result<(Vec<Assembly>, SourceMap)> {
+    let mut sources = parse_inputs(options)?;
+    if options.fold {
+        let folded: usize = sources
+            .iter_mut()
+            .map(|source| fold_lines(&mut source.commands, &mut source.lines))
+            .sum();
+        let folded = count(folded, "VM command");
+        eprintln!("{}: folded away {folded}", options.describe());
+    }
+
+    let defines_sys_init = sources.iter().any(|source| source.defines("Sys.init"));
+    let config = Config {
+        bootstrap: options
+            .bootstrap
+            .unwrap_or(options.is_program() && defines_sys_init),
+        sentinels: options.sentinels,
+        compact: options.compact,
+        cache_top: options.cache_top,
+    };
+    let (mut program, map) = translate_mapped(&sources, &config)?;
+    if options.peephole {
+        let stats = optimize(&mut program);
+        eprintln!("{}: {stats}", options.describe());
+    }
+    Ok((program, map))
+}
+
+/// Write to a temporary file and rename it over `output`, so a failure never
+/// leaves a half-written `.asm` behind
+fn write_output(output: &Path, contents: &str) -> eyre::Result<()> {
+    let filename = output
+        .file_name()
+        .ok_or(eyre::eyre!("Not a file: {}", output.display()))?
+        .to_string_lossy();
+    let tmp = output.with_file_name(format!(".{filename}.tmp"));
+    let result = (|| {
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        writer.write_all(contents.as_bytes())?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp, output)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// [`write_output`], or print to stdout if `output` is `None`
+fn write_to(output: Option<&Path>, contents: &str) -> eyre::Result<()> {
+    match output {
+        Some(output) => write_output(output, contents),
+        None => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(contents.as_bytes())?;
+            stdout.flush()?;
+            Ok(())
+        }
+    }
+}
+
+/// `{"instructions", "source_map"}`, instructions indexed by ROM address
+fn render_json(program: &[Assembly], map: &SourceMap) -> String {
+    let instructions = program
+        .iter()
+        .filter(|asm| asm.is_instruction())
+        // Symbols never need escaping
+        .map(|asm| format!("    \"{asm}\""))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    format!(
+        "{{\n  \"instructions\": [\n{instructions}\n  ],\n  \"source_map\": {}\n}}\n",
+        map.to_json(program).trim_end().replace('\n', "\n  ")
+    )
+}
+
+fn translate_to_file(options: &Options) -> eyre::Result<()> {
+    let (program, map) = translate_path(options)?;
+    let contents = match options.emit {
+        Emit::Asm => render_with(&program, options.comments),
+        Emit::Hack => render_hack(&assemble(&program)?),
+        Emit::Json => render_json(&program, &map),
+    };
+    let output = options.output_path()?;
+    let map_path = match (options.source_map, &output) {
+        (false, _) => None,
+        (true, Some(output)) => Some(output.with_extension("map.json")),
+        (true, None) => return Err(eyre::eyre!("--source-map requires an output file")),
+    };
+    write_to(output.as_deref(), &contents)?;
+    // Only once the output it describes is in place
+    if let Some(map_path) = map_path {
+        write_output(&map_path, &map.to_json(&program))?;
     }
     Ok(())
 }
 
-fn main() -> eyre::Result<()> {
-    if let Some(filepath) = env::args().nth(1).map(PathBuf::from) {
-        run(&filepath)?;
+fn assemble_file(options: &Options) -> eyre::Result<()> {
+    let path = options.paths[0].as_path();
+    let program = parse_asm(&read(path)?).map_err(|e| eyre::eyre!("in {}: {e}", path.display()))?;
+    write_to(
+        options.output_path()?.as_deref(),
+        &render_hack(&assemble(&program)?),
+    )
+}
+
+/// Run the translation of `options.path` until it halts and print the
+/// pointers and the stack
+fn execute(options: &Options) -> eyre::Result<()> {
+    if let [path] = options.paths.as_slice() {
+        if path.extension().is_some_and(|ext| ext == "tst") {
+            return run_script(options, path);
+        }
+    }
+    let (program, _) = translate_path(options)?;
+    let mut emulator = Emulator::from_assembly(&program)?;
+    // Where the bootstrap, or a test script, would start the stack
+    emulator.poke(0, 256);
+    let steps = emulator.run(options.steps)?;
+    println!("halted after {}", count(steps as usize, "step"));
+    for (name, addr) in [("SP", 0), ("LCL", 1), ("ARG", 2), ("THIS", 3), ("THAT", 4)] {
+        println!("{name:<4} = {}", emulator.peek(addr));
+    }
+    let sp = emulator.peek(0).clamp(256, 2048) as usize;
+    let stack = emulator.ram()[256..sp]
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>();
+    println!("stack: [{}]", stack.join(", "));
+    Ok(())
+}
+
+/// Run a `.tst` script against the translation of its directory, comparing
+/// with the script's `.cmp` file if it names one
+fn run_script(options: &Options, path: &Path) -> eyre::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let script =
+        TestScript::parse(&read(path)?).map_err(|e| eyre::eyre!("in {}: {e}", path.display()))?;
+    let (program, _) = translate_path(&Options {
+        paths: vec![dir.clone()],
+        output: None,
+        name: None,
+        ..*options
+    })?;
+    let emulator = Emulator::from_assembly(&program)?;
+    let output = script
+        .run(|_| Ok(emulator.clone()))
+        .map_err(|e| eyre::eyre!("{}: {e}", path.display()))?;
+    match output.compare_to {
+        Some(cmp) => {
+            let expected = read(&dir.join(&cmp))?;
+            compare(&output.output, &expected)
+                .map_err(|e| eyre::eyre!("{} against {cmp}: {e}", path.display()))?;
+            println!("{}: comparison ended successfully", path.display());
+        }
+        None => print!("{}", output.output),
     }
     Ok(())
 }
 
+/// Parse and validate without translating
+/// `n` and `noun`, plural unless there is exactly one
+fn count(n: usize, noun: &str) -> String {
+    format!("{n} {noun}{}", if n == 1 { "" } else { "s" })
+}
+
+fn check(options: &Options) -> eyre::Result<()> {
+    let sources = parse_inputs(options)?;
+    validate(&sources)?;
+    let commands: usize = sources.iter().map(|source| source.commands.len()).sum();
+    println!(
+        "{}: {} in {} are valid",
+        options.describe(),
+        count(commands, "command"),
+        count(sources.len(), "file")
+    );
+    Ok(())
+}
+
+fn run(options: &Options) -> eyre::Result<()> {
+    match options.command {
+        Subcommand::Translate => translate_to_file(options),
+        Subcommand::Assemble => assemble_file(options),
+        Subcommand::Run => execute(options),
+        Subcommand::Check => check(options),
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match Options::from_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {e}\n\nRun `VMTranslator --help` for usage.");
+            return ExitCode::from(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("error: {e:#}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vm_translator_rs::translate;
+
+    /// Run `options` with a fresh temporary output file ending in `extension`
+    fn run_to_temp(options: Options, extension: &str) -> PathBuf {
+        // Tests run in parallel, often on the same input
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let output = env::temp_dir().join(format!(
+            "vm-translator-{}-{}-{}.{extension}",
+            std::process::id(),
+            RUNS.fetch_add(1, Ordering::Relaxed),
+            file_stem(&options.paths[0]).unwrap()
+        ));
+        run(&Options {
+            output: Some(output.clone()),
+            ..options
+        })
+        .unwrap();
+        output
+    }
 
     #[test]
     fn test_basic() {
-        run("extra/BasicTest/BasicTest.vm").unwrap();
+        let output = run_to_temp(Options::new("extra/BasicTest/BasicTest.vm"), "asm");
+        let asm = fs::read_to_string(&output).unwrap();
+        assert!(asm.ends_with("($END)\n@$END\n0;JMP\n"));
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_basic_loop() {
+        fs::remove_file(run_to_temp(
+            Options::new("extra/BasicLoop/BasicLoop.vm"),
+            "asm",
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_simple_function() {
+        let options = Options::new("extra/SimpleFunction/SimpleFunction.vm");
+        fs::remove_file(run_to_temp(options, "asm")).unwrap();
+    }
+
+    #[test]
+    fn test_failed_translation_keeps_output() {
+        let dir = env::temp_dir().join(format!("vm-translator-{}-invalid", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("Invalid.vm");
+        fs::write(&input, "push constant 1\nfoo\n").unwrap();
+        fs::write(dir.join("Invalid.asm"), "previous").unwrap();
+
+        assert!(run(&Options::new(&input)).is_err());
+        assert_eq!(
+            fs::read_to_string(dir.join("Invalid.asm")).unwrap(),
+            "previous"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_output_writes_no_source_map() {
+        let dir = env::temp_dir().join(format!("vm-translator-{}-no-map", std::process::id()));
+        // A directory where the output should go makes writing it fail
+        let output = dir.join("Out.asm");
+        fs::create_dir_all(&output).unwrap();
+        let options = Options {
+            output: Some(output.clone()),
+            source_map: true,
+            ..Options::new("extra/SimpleAdd/SimpleAdd.vm")
+        };
+        assert!(run(&options).is_err());
+        assert!(!output.with_extension("map.json").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Run every `extra/Foo/Foo.tst` against the translation of `extra/Foo`
+    fn run_extra_scripts(optimize: bool, compact: bool) {
+        let mut dirs = fs::read_dir("extra")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        dirs.sort();
+        for dir in dirs {
+            let name = file_stem(&dir).unwrap().to_string();
+            let script = fs::read_to_string(dir.join(format!("{name}.tst"))).unwrap();
+            let options = Options {
+                fold: optimize,
+                cache_top: optimize,
+                peephole: optimize,
+                compact,
+                ..Options::new(&dir)
+            };
+            let output = TestScript::parse(&script)
+                .unwrap()
+                .run(|_| {
+                    let (program, _) = translate_path(&options).unwrap();
+                    Ok(Emulator::from_assembly(&program).unwrap())
+                })
+                .unwrap_or_else(|e| panic!("{name}: {e}"));
+            let cmp = fs::read_to_string(dir.join(output.compare_to.unwrap())).unwrap();
+            if let Err(e) = compare(&output.output, &cmp) {
+                panic!("{name}: {e}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_extra_scripts() {
+        run_extra_scripts(false, false);
+    }
+
+    #[test]
+    fn test_extra_scripts_optimized() {
+        run_extra_scripts(true, false);
+    }
+
+    #[test]
+    fn test_extra_scripts_compact() {
+        run_extra_scripts(true, true);
+    }
+
+    #[test]
+    fn test_output_path() {
+        assert_eq!(
+            Options::new("extra/BasicTest/BasicTest.vm")
+                .output_path()
+                .unwrap(),
+            Some(PathBuf::from("extra/BasicTest/BasicTest.asm"))
+        );
+        assert_eq!(
+            Options::new("extra/StaticsTest").output_path().unwrap(),
+            Some(PathBuf::from("extra/StaticsTest/StaticsTest.asm"))
+        );
+        let options = Options::from_args(["-o", "out.asm", "Foo.vm"].map(String::from).into_iter())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            options.output_path().unwrap(),
+            Some(PathBuf::from("out.asm"))
+        );
+        let options =
+            Options::from_args(["--emit", "hack", "Foo.vm"].map(String::from).into_iter())
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            options.output_path().unwrap(),
+            Some(PathBuf::from("Foo.hack"))
+        );
+    }
+
+    #[test]
+    fn test_source_map() {
+        let options = Options {
+            source_map: true,
+            ..Options::new("extra/SimpleAdd/SimpleAdd.vm")
+        };
+        let output = run_to_temp(options, "asm");
+        let map = fs::read_to_string(output.with_extension("map.json")).unwrap();
+        assert!(map.contains(r#""file": "SimpleAdd.vm", "line": 10, "command": "add"}"#));
+        fs::remove_file(output.with_extension("map.json")).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_comments_none() {
+        let options = args(&["--comments", "none", "extra/BasicTest"])
+            .unwrap()
+            .unwrap();
+        let output = run_to_temp(options, "asm");
+        let asm = fs::read_to_string(&output).unwrap();
+        assert!(!asm.contains("//"));
+        fs::remove_file(output).unwrap();
+    }
+
+    fn args(args: &[&str]) -> Result<Option<Options>, UsageError> {
+        Options::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_usage_errors() {
+        let error = |a: &[&str]| args(a).err().unwrap().to_string();
+        assert_eq!(error(&[]), "missing input path");
+        assert_eq!(error(&["check"]), "missing input path");
+        assert_eq!(error(&["--fast", "Foo.vm"]), "unknown option --fast");
+        assert_eq!(
+            error(&["assemble", "Foo.asm", "Bar.asm"]),
+            "unexpected argument Bar.asm"
+        );
+        assert_eq!(
+            error(&["check", "--emit", "hack", "Foo.vm"]),
+            "--emit does not apply to check"
+        );
+        assert_eq!(
+            error(&["--emit", "elf", "Foo.vm"]),
+            "unknown output format elf, expected asm, hack or json"
+        );
+        assert_eq!(
+            error(&["run", "Foo.vm", "--steps"]),
+            "--steps requires a number"
+        );
+        assert!(args(&["help"]).unwrap().is_none());
+        assert!(args(&["translate", "-h"]).unwrap().is_none());
+        let options = args(&["run", "--optimize", "Foo.vm"]).unwrap().unwrap();
+        assert_eq!(options.command, Subcommand::Run);
+        assert!(options.fold && options.cache_top && options.peephole);
+        let options = args(&["--cache-top", "Foo.vm"]).unwrap().unwrap();
+        assert!(options.cache_top && !options.fold && !options.peephole);
+    }
+
+    #[test]
+    fn test_count() {
+        assert_eq!(count(1, "file"), "1 file");
+        assert_eq!(count(0, "file"), "0 files");
+        assert_eq!(count(3, "VM command"), "3 VM commands");
+    }
+
+    #[test]
+    fn test_file_list() {
+        let files = ["Class1.vm", "Class2.vm", "Sys.vm"].map(|f| format!("extra/StaticsTest/{f}"));
+        let options = args(&files.each_ref().map(String::as_str))
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.output_path().unwrap(), None);
+        // Bootstrapped like the directory, since the files define Sys.init
+        assert_eq!(
+            translate_path(&options).unwrap().0,
+            translate_path(&Options::new("extra/StaticsTest"))
+                .unwrap()
+                .0
+        );
+
+        let options = args(&[&files[0], "extra/StaticsTest"]).unwrap().unwrap();
+        assert_eq!(
+            translate_path(&options).unwrap_err().to_string(),
+            "more than one input is named Class1.vm"
+        );
+        assert_eq!(
+            args(&["-", "Foo.vm", "-"]).err().unwrap().to_string(),
+            "- can only be given once"
+        );
+        assert_eq!(
+            args(&["--name", "Foo", "-", "Foo.vm"])
+                .err()
+                .unwrap()
+                .to_string(),
+            "--name requires a single input"
+        );
+        assert_eq!(args(&["-"]).unwrap().unwrap().output_path().unwrap(), None);
+    }
+
+    #[test]
+    fn test_name_overrides_static_prefix() {
+        let options = args(&["--name", "Renamed", "extra/StaticTest/StaticTest.vm"])
+            .unwrap()
+            .unwrap();
+        let program = translate_path(&options).unwrap().0;
+        assert!(program.contains(&Assembly::addr_sym("Renamed.3")));
+        assert!(!program.contains(&Assembly::addr_sym("StaticTest.3")));
+    }
+
+    #[test]
+    fn test_emit_json() {
+        let options = Options {
+            emit: Emit::Json,
+            ..Options::new("extra/SimpleAdd/SimpleAdd.vm")
+        };
+        let output = run_to_temp(options, "json");
+        let json = fs::read_to_string(&output).unwrap();
+        assert!(json.starts_with("{\n  \"instructions\": [\n    \"@7\",\n"));
+        assert!(json.contains("\"line\": 10, \"command\": \"add\"}\n  ]\n}\n"));
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_assemble() {
+        let asm = run_to_temp(Options::new("extra/BasicTest/BasicTest.vm"), "asm");
+        let options = Options {
+            emit: Emit::Hack,
+            ..Options::new("extra/BasicTest/BasicTest.vm")
+        };
+        let hack = run_to_temp(options, "hack");
+        run(&args(&["assemble", asm.to_str().unwrap()]).unwrap().unwrap()).unwrap();
+        let assembled = asm.with_extension("hack");
+        assert_eq!(
+            fs::read_to_string(&assembled).unwrap(),
+            fs::read_to_string(&hack).unwrap()
+        );
+        for file in [asm, hack, assembled] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_emit_hack() {
+        let options = Options {
+            emit: Emit::Hack,
+            ..Options::new("extra/BasicTest/BasicTest.vm")
+        };
+        let output = run_to_temp(options, "hack");
+        let hack = fs::read_to_string(&output).unwrap();
+        assert!(hack
+            .lines()
+            .all(|line| line.len() == 16 && line.chars().all(|c| c == '0' || c == '1')));
+        fs::remove_file(output).unwrap();
+    }
+
+    /// Caching the stack top in D shrinks every sample program
+    #[test]
+    fn test_cache_top_saves_instructions() {
+        for entry in fs::read_dir("extra").unwrap() {
+            let dir = entry.unwrap().path();
+            let mut sources = Vec::new();
+            for file in vm_files(&dir).unwrap() {
+                sources.push(parse_file(&file).unwrap());
+            }
+            let count = |cache_top| {
+                let config = Config {
+                    cache_top,
+                    ..Config::default()
+                };
+                translate(&sources, &config).unwrap().len()
+            };
+            let (before, after) = (count(false), count(true));
+            assert!(
+                after < before * 9 / 10,
+                "{}: {after} of {before}",
+                dir.display()
+            );
+        }
+    }
+
+    #[test]
+    fn test_directory() {
+        let program = translate_path(&Options::new("extra/StaticsTest"))
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>();
+        assert!(program.contains(&"@Class1.0".to_string()));
+        assert!(program.contains(&"@Class2.0".to_string()));
+        assert!(program.contains(&"(Sys.init)".to_string()));
+        // Sys.init is defined, so the program starts with the bootstrap code
+        assert_eq!(program[1..3], ["@256", "D=A"]);
+    }
+
+    #[test]
+    fn test_no_bootstrap() {
+        let mut options = Options::new("extra/StaticsTest");
+        options.bootstrap = Some(false);
+        let (program, _) = translate_path(&options).unwrap();
+        assert_eq!(program[0].to_string(), "// function Class1.set 0");
     }
 }
@@ -0,0 +1,217 @@
+//! Peephole optimisation of translated programs
+//!
+//! Each VM command is translated in isolation, so adjacent commands undo each
+//! other's work: a push increments SP only for the next pop to decrement it,
+//! and `@SP`/`A=M` are reloaded although A already holds them. The pass works
+//! on a whole program across command boundaries, only within straight-line
+//! runs of instructions since any label may be a jump target.
+//!
+//! Removing a reload of `@X`/`A=M` assumes a pointer never points to itself,
+//! e.g. the stack never overlaps the SP register.
+
+use std::fmt::Display;
+
+use crate::assembly::{Assembly, Comp, Dest, Jump};
+
+/// Instructions removed by [`optimize`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Instructions before optimising, not counting labels and comments
+    pub before: usize,
+    /// Increments of SP immediately undone by a decrement, or vice versa
+    pub push_pop: usize,
+    /// `@X`, `@X`/`A=M` and `D=M` loading what a register already holds
+    pub redundant_loads: usize,
+    /// Assignments to D overwritten before being read
+    pub dead_assignments: usize,
+}
+
+impl Stats {
+    pub fn saved(&self) -> usize {
+        self.push_pop + self.redundant_loads + self.dead_assignments
+    }
+
+    pub fn after(&self) -> usize {
+        self.before - self.saved()
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "saved {} of {} instructions ({} push/pop, {} redundant loads, {} dead assignments)",
+            self.saved(),
+            self.before,
+            self.push_pop,
+            self.redundant_loads,
+            self.dead_assignments
+        )
+    }
+}
+
+fn writes(dest: Dest, register: char) -> bool {
+    dest.to_string().contains(register)
+}
+
+fn reads(comp: Comp, register: char) -> bool {
+    comp.to_string().contains(register)
+}
+
+/// What the optimiser knows about the A register
+#[derive(Debug, Clone, PartialEq)]
+enum Known {
+    Unknown,
+    /// Loaded by this `@X`
+    Address(Assembly),
+    /// Loaded by this `@X` followed by `A=M`
+    Deref(Assembly),
+}
+
+/// Remove redundant instructions from `program` until none are left
+pub fn optimize(program: &mut Vec<Assembly>) -> Stats {
+    let mut stats = Stats {
+        before: program.iter().filter(|asm| asm.is_instruction()).count(),
+        ..Stats::default()
+    };
+    loop {
+        let saved = stats.saved();
+        *program = forward(std::mem::take(program), &mut stats);
+        remove_dead_assignments(program, &mut stats);
+        if stats.saved() == saved {
+            return stats;
+        }
+    }
+}
+
+/// Drop loads of values already in A or D, and SP increments undone by the
+/// following decrement
+fn forward(program: Vec<Assembly>, stats: &mut Stats) -> Vec<Assembly> {
+    let mut out: Vec<Assembly> = Vec::with_capacity(program.len());
+    let mut a = Known::Unknown;
+    // D holds RAM[A]
+    let mut d_is_m = false;
+    let mut iter = program.into_iter().peekable();
+    while let Some(asm) = iter.next() {
+        match &asm {
+            Assembly::Comment(_) => {}
+            Assembly::Label(_) => {
+                a = Known::Unknown;
+                d_is_m = false;
+            }
+            Assembly::Address(_) | Assembly::VariableSymbol(_) => {
+                if a == Known::Address(asm.clone()) {
+                    stats.redundant_loads += 1;
+                    continue;
+                }
+                let reload = Assembly::assign(Dest::A, Comp::M);
+                if a == Known::Deref(asm.clone()) && iter.peek() == Some(&reload) {
+                    iter.next();
+                    stats.redundant_loads += 2;
+                    continue;
+                }
+                a = Known::Address(asm.clone());
+                d_is_m = false;
+            }
+            &Assembly::Command { dest, comp, jump } => {
+                if jump == Jump::None && dest == Dest::D && comp == Comp::M && d_is_m {
+                    stats.redundant_loads += 1;
+                    continue;
+                }
+                if jump == Jump::None && dest == Dest::M {
+                    let undoes = match comp {
+                        Comp::Mplus1 => Some(Assembly::assign(Dest::M, Comp::Mminus1)),
+                        Comp::Mminus1 => Some(Assembly::assign(Dest::M, Comp::Mplus1)),
+                        _ => None,
+                    };
+                    let last = out
+                        .iter()
+                        .rposition(|asm| !matches!(asm, Assembly::Comment(_)));
+                    if undoes.is_some() && last.map(|i| &out[i]) == undoes.as_ref() {
+                        out.remove(last.unwrap());
+                        stats.push_pop += 2;
+                        d_is_m = false;
+                        continue;
+                    }
+                }
+                if writes(dest, 'A') {
+                    a = match (&a, dest, comp) {
+                        (Known::Address(x), Dest::A, Comp::M) => Known::Deref(x.clone()),
+                        _ => Known::Unknown,
+                    };
+                    d_is_m = false;
+                } else if writes(dest, 'D') && writes(dest, 'M') {
+                    d_is_m = true;
+                } else if writes(dest, 'M') {
+                    d_is_m = comp == Comp::D;
+                } else if writes(dest, 'D') {
+                    d_is_m = comp == Comp::M;
+                }
+            }
+        }
+        out.push(asm);
+    }
+    out
+}
+
+/// Drop `D=...` whose value is overwritten before anything reads D
+fn remove_dead_assignments(program: &mut Vec<Assembly>, stats: &mut Stats) {
+    // D is assumed live wherever control may leave the straight-line code
+    let mut live = true;
+    let mut dead = vec![false; program.len()];
+    for (i, asm) in program.iter().enumerate().rev() {
+        match asm {
+            Assembly::Label(_) => live = true,
+            &Assembly::Command { dest, comp, jump } => {
+                if jump != Jump::None {
+                    live = true;
+                } else if dest == Dest::D && !live {
+                    dead[i] = true;
+                    stats.dead_assignments += 1;
+                    continue;
+                }
+                live = (live && !writes(dest, 'D')) || reads(comp, 'D');
+            }
+            _ => {}
+        }
+    }
+    let mut dead = dead.into_iter();
+    program.retain(|_| !dead.next().unwrap());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembly::parse_asm;
+
+    fn optimized(source: &str) -> (String, Stats) {
+        let mut program = parse_asm(source).unwrap();
+        let stats = optimize(&mut program);
+        (crate::render(&program), stats)
+    }
+
+    #[test]
+    fn test_push_pop_pair() {
+        // push constant 7, then the start of `add`
+        let (asm, stats) = optimized("@7\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n@SP\nM=M-1\nA=M\nD=M\n");
+        assert_eq!(asm, "@7\nD=A\n@SP\nA=M\nM=D\n");
+        assert_eq!(stats.push_pop, 2);
+        assert_eq!(stats.redundant_loads, 4);
+        assert_eq!(stats.after(), 5);
+    }
+
+    #[test]
+    fn test_dead_assignment() {
+        let (asm, stats) = optimized("@5\nD=M\n@6\nD=A\nM=D\n");
+        assert_eq!(asm, "@5\n@6\nD=A\nM=D\n");
+        assert_eq!(stats.dead_assignments, 1);
+    }
+
+    #[test]
+    fn test_labels_stop_optimisation() {
+        let source = "@SP\nM=M+1\n(LOOP)\n@SP\nM=M-1\nD=M\n@LOOP\nD;JNE\n";
+        let (asm, stats) = optimized(source);
+        assert_eq!(asm, source);
+        assert_eq!(stats.saved(), 0);
+    }
+}
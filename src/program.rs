@@ -0,0 +1,317 @@
+use std::fmt::Display;
+
+use crate::{
+    assembly::Assembly,
+    babel::{Babel, Translation},
+    commands::{self, segment::SegmentError, segment::MAX_STATICS, Command},
+};
+
+/// The parsed commands of one `.vm` file
+#[derive(Debug, Clone)]
+pub struct Source {
+    /// File stem, used as the prefix of static symbols
+    pub name: String,
+    pub commands: Vec<Command>,
+    /// 1-based line of each command, empty if not known
+    pub lines: Vec<usize>,
+    /// The file's text, to find the column of a command in errors
+    pub text: Option<String>,
+}
+
+impl Source {
+    pub fn new<S: Into<String>>(name: S, commands: Vec<Command>) -> Self {
+        Self {
+            name: name.into(),
+            commands,
+            lines: Vec::new(),
+            text: None,
+        }
+    }
+
+    /// Record the line of each command, e.g. from [`crate::commands::source::parse_numbered`]
+    pub fn with_lines(self, lines: Vec<usize>) -> Self {
+        Self { lines, ..self }
+    }
+
+    /// Keep the text the commands were parsed from, see [`Source::with_lines`]
+    pub fn with_text<S: Into<String>>(self, text: S) -> Self {
+        Self {
+            text: Some(text.into()),
+            ..self
+        }
+    }
+
+    /// 1-based line and column of command `i`, as far as they are known
+    fn position(&self, i: usize) -> (Option<usize>, Option<usize>) {
+        let Some(&line) = self.lines.get(i) else {
+            return (None, None);
+        };
+        let column = self
+            .text
+            .as_deref()
+            .and_then(|text| text.lines().nth(line - 1))
+            .map(|text| text.chars().take_while(|c| c.is_whitespace()).count() + 1);
+        (Some(line), column)
+    }
+
+    fn error(&self, i: Option<usize>, error: SegmentError) -> ValidationError {
+        let (line, column) = i.map_or((None, None), |i| self.position(i));
+        ValidationError {
+            name: self.name.clone(),
+            line,
+            column,
+            error,
+        }
+    }
+
+    /// Whether this file defines the function `name`, e.g. `Sys.init`
+    pub fn defines(&self, name: &str) -> bool {
+        self.commands
+            .iter()
+            .any(|cmd| matches!(cmd, Command::Function(f, _) if f == name))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Emit code setting SP and calling `Sys.init` before the program
+    pub bootstrap: bool,
+    /// Initialise LCL/ARG/THIS/THAT to the test-harness sentinel values
+    pub sentinels: bool,
+    /// Jump to shared routines for comparisons, calls and returns instead of
+    /// inlining each one, for programs that would not fit in ROM
+    pub compact: bool,
+    /// Keep the top of the stack in D between commands
+    pub cache_top: bool,
+}
+
+/// A [`SegmentError`] together with the file, and where known the command, it is in
+#[derive(Debug, thiserror::Error)]
+pub struct ValidationError {
+    pub name: String,
+    /// 1-based line of the offending command
+    pub line: Option<usize>,
+    /// 1-based column of the offending command
+    pub column: Option<usize>,
+    pub error: SegmentError,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "in {}.vm", self.name)?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        if let Some(column) = self.column {
+            write!(f, ":{column}")?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+/// Check segment indices of every file and the total number of statics
+pub fn validate(sources: &[Source]) -> Result<(), ValidationError> {
+    let mut statics = 0;
+    for source in sources {
+        for (i, cmd) in source.commands.iter().enumerate() {
+            commands::validate_command(cmd).map_err(|error| source.error(Some(i), error))?;
+        }
+        statics += commands::static_count(&source.commands);
+        if statics > MAX_STATICS {
+            return Err(source.error(None, SegmentError::TooManyStatics(statics)));
+        }
+    }
+    Ok(())
+}
+
+/// Validate and translate `sources` into one program ending in the [`crate::babel::END`] loop
+pub fn translate(sources: &[Source], config: &Config) -> Result<Vec<Assembly>, ValidationError> {
+    translate_commands(sources, config, |_, _, _| {})
+}
+
+/// [`translate`], calling `on_command` with each source, the index of its
+/// next command and the program so far
+pub(crate) fn translate_commands<F>(
+    sources: &[Source],
+    config: &Config,
+    mut on_command: F,
+) -> Result<Vec<Assembly>, ValidationError>
+where
+    F: FnMut(&Source, usize, &[Assembly]),
+{
+    validate(sources)?;
+
+    let mut babel = Babel::empty(sources.first().map_or("", |s| s.name.as_str()));
+    babel.set_compact(config.compact);
+    babel.set_cache_top(config.cache_top);
+    let mut program = Vec::new();
+    if config.bootstrap {
+        program.extend(babel.bootstrap(config.sentinels));
+    }
+    for source in sources {
+        babel.set_basename(source.name.as_str());
+        for (i, cmd) in source.commands.iter().enumerate() {
+            on_command(source, i, &program);
+            program.extend(
+                babel
+                    .translate(cmd)
+                    .map_err(|error| source.error(Some(i), error))?,
+            );
+        }
+    }
+    program.extend(babel.flush());
+    program.extend(Translation::finish());
+    program.extend(babel.routines());
+    Ok(program)
+}
+
+/// Which comments [`render_with`] writes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Comments {
+    /// Instructions and labels only
+    None,
+    /// The VM command each block of code was translated from
+    #[default]
+    Source,
+    /// VM commands, and what every instruction does at the end of its line
+    Verbose,
+}
+
+/// Hack assembly text, one instruction per line, with VM command comments
+pub fn render(program: &[Assembly]) -> String {
+    render_with(program, Comments::Source)
+}
+
+/// [`render`], writing the given `comments`
+pub fn render_with(program: &[Assembly], comments: Comments) -> String {
+    let mut out = String::new();
+    for instruction in program {
+        let explanation = instruction
+            .explain()
+            .filter(|_| comments == Comments::Verbose);
+        let line = match (instruction, explanation) {
+            (Assembly::Comment(_), _) if comments == Comments::None => continue,
+            (_, Some(explanation)) => format!("{:<16} // {explanation}", instruction.to_string()),
+            (_, None) => instruction.to_string(),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validation_error_names_file() {
+        let sources = [
+            Source::new("Main", vec!["push temp 1".parse().unwrap()]),
+            Source::new("Math", vec!["push temp 8".parse().unwrap()]),
+        ];
+        let err = translate(&sources, &Config::default()).unwrap_err();
+        assert_eq!(err.name, "Math");
+        assert_eq!(
+            err.to_string(),
+            "in Math.vm: index 8 out of range for temp segment, maximum is 7"
+        );
+
+        let text = "push temp 1\n\n  pop pointer 2 // oops\n";
+        let (lines, commands) = crate::parse_numbered("Main.vm", text)
+            .unwrap()
+            .into_iter()
+            .unzip();
+        let sources = [Source::new("Main", commands)
+            .with_lines(lines)
+            .with_text(text)];
+        let err = validate(&sources).unwrap_err();
+        assert_eq!((err.line, err.column), (Some(3), Some(3)));
+    }
+
+    #[test]
+    fn test_render_round_trip() {
+        let commands =
+            crate::parse("push constant 7\nlabel L\nif-goto L\ncall Foo.bar 0\neq").unwrap();
+        let program = translate(&[Source::new("Main", commands)], &Config::default()).unwrap();
+        assert_eq!(crate::parse_asm(&render(&program)).unwrap(), program);
+    }
+
+    #[test]
+    fn test_render_comments() {
+        let commands = crate::parse("push constant 7\nadd").unwrap();
+        let program = translate(&[Source::new("Main", commands)], &Config::default()).unwrap();
+        let source = render(&program);
+        assert!(source.starts_with("// push constant 7\n@7\n"));
+        assert!(source.contains("// add\n"));
+
+        let none = render_with(&program, Comments::None);
+        assert!(!none.contains("//"));
+        assert_eq!(none.lines().count(), program.len() - 2);
+
+        let verbose = render_with(&program, Comments::Verbose);
+        assert!(verbose.contains("\nD=A              // D = A\n"));
+        assert!(verbose.contains("\n($END)\n"));
+        // Explanations are trailing comments, so every mode parses back alike
+        for text in [&none, &verbose] {
+            let parsed = crate::parse_asm(text).unwrap();
+            let instructions = |p: &[Assembly]| {
+                p.iter()
+                    .filter(|asm| !matches!(asm, Assembly::Comment(_)))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(instructions(&parsed), instructions(&program));
+        }
+    }
+
+    #[test]
+    fn test_compact_comparisons() {
+        let commands = crate::parse(&"push constant 1\npush constant 2\nlt\n".repeat(10)).unwrap();
+        let sources = [Source::new("Main", commands)];
+        let inline = translate(&sources, &Config::default()).unwrap();
+        let config = Config {
+            compact: true,
+            ..Config::default()
+        };
+        let compact = translate(&sources, &config).unwrap();
+        assert!(compact.len() < inline.len() / 2);
+        // One routine shared by every `lt`
+        let routines = compact
+            .iter()
+            .filter(|asm| **asm == Assembly::label("$lt"))
+            .count();
+        assert_eq!(routines, 1);
+    }
+
+    #[test]
+    fn test_compact_calls() {
+        let commands = crate::parse(
+            "function Sys.init 0
+            push constant 3
+            call Sys.double 1
+            call Sys.double 1
+            pop static 0
+            label HALT
+            goto HALT
+            function Sys.double 1
+            push argument 0
+            pop local 0
+            push local 0
+            push local 0
+            add
+            return",
+        )
+        .unwrap();
+        let config = Config {
+            bootstrap: true,
+            compact: true,
+            ..Config::default()
+        };
+        let program = translate(&[Source::new("Sys", commands)], &config).unwrap();
+        let mut emulator = crate::Emulator::from_assembly(&program).unwrap();
+        emulator.run(10_000).unwrap();
+        assert_eq!(emulator.peek(16), 12);
+        assert_eq!(emulator.peek(0), 261);
+    }
+}
@@ -0,0 +1,178 @@
+//! Mapping from ROM addresses of a translated program back to VM commands
+//!
+//! The code of every VM command starts with a comment, see
+//! [`crate::Translation`]. The map identifies commands by counting comments
+//! rather than by position, so passes like [`crate::optimize`] that remove
+//! instructions but keep every comment leave it valid.
+
+use std::fmt::Write;
+
+use crate::{
+    assembly::Assembly,
+    babel::END,
+    program::{translate_commands, Config, Source, ValidationError},
+};
+
+/// The VM command some code was translated from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    /// File stem, as in [`Source::name`]
+    pub file: String,
+    /// 1-based line, if the source was parsed with line numbers
+    pub line: Option<usize>,
+    /// The command as VM source text
+    pub command: String,
+}
+
+/// ROM addresses `start..end` were translated from `origin`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping<'a> {
+    pub start: u16,
+    pub end: u16,
+    pub origin: &'a Origin,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    /// Index among the program's comments of the one starting each command
+    commands: Vec<(usize, Origin)>,
+}
+
+impl SourceMap {
+    /// ROM ranges of every command in `program`, which must be the program
+    /// this map was built with
+    ///
+    /// Code before the first command, like the bootstrap, and after the
+    /// [`END`] loop, like shared routines, has no origin.
+    pub fn resolve<'a>(&'a self, program: &[Assembly]) -> Vec<Mapping<'a>> {
+        let mut mappings: Vec<Mapping> = Vec::with_capacity(self.commands.len());
+        let mut next = self.commands.iter().peekable();
+        let mut address = 0;
+        let mut comments = 0;
+        for asm in program {
+            match asm {
+                Assembly::Comment(_) => {
+                    if let Some((_, origin)) = next.next_if(|(comment, _)| *comment == comments) {
+                        if let Some(last) = mappings.last_mut() {
+                            last.end = address;
+                        }
+                        mappings.push(Mapping {
+                            start: address,
+                            end: address,
+                            origin,
+                        });
+                    }
+                    comments += 1;
+                }
+                Assembly::Label(label) if label == END && next.peek().is_none() => break,
+                asm if asm.is_instruction() => address += 1,
+                _ => {}
+            }
+        }
+        if let Some(last) = mappings.last_mut() {
+            last.end = address;
+        }
+        mappings
+    }
+
+    /// JSON array of `{"start", "end", "file", "line", "command"}` objects,
+    /// one per command with the ROM range `start..end` it occupies
+    pub fn to_json(&self, program: &[Assembly]) -> String {
+        let mut out = String::from("[\n");
+        let mappings = self.resolve(program);
+        for (i, mapping) in mappings.iter().enumerate() {
+            let line = mapping
+                .origin
+                .line
+                .map_or("null".to_string(), |line| line.to_string());
+            write!(
+                out,
+                "  {{\"start\": {}, \"end\": {}, \"file\": {}, \"line\": {}, \"command\": {}}}",
+                mapping.start,
+                mapping.end,
+                json_string(&format!("{}.vm", mapping.origin.file)),
+                line,
+                json_string(&mapping.origin.command)
+            )
+            .expect("writing to a String cannot fail");
+            out.push_str(if i + 1 < mappings.len() { ",\n" } else { "\n" });
+        }
+        out.push_str("]\n");
+        out
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// [`crate::translate`], also returning where each command's code is
+pub fn translate_mapped(
+    sources: &[Source],
+    config: &Config,
+) -> Result<(Vec<Assembly>, SourceMap), ValidationError> {
+    let mut map = SourceMap::default();
+    let (mut comments, mut seen) = (0, 0);
+    let program = translate_commands(sources, config, |source, i, program| {
+        comments += program[seen..]
+            .iter()
+            .filter(|asm| matches!(asm, Assembly::Comment(_)))
+            .count();
+        seen = program.len();
+        map.commands.push((
+            comments,
+            Origin {
+                file: source.name.clone(),
+                line: source.lines.get(i).copied(),
+                command: source.commands[i].to_string(),
+            },
+        ));
+    })?;
+    Ok((program, map))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let source = Source::new(
+            "Main",
+            crate::parse("push constant 7\npush constant 8\nadd").unwrap(),
+        )
+        .with_lines(vec![1, 2, 4]);
+        let (mut program, map) = translate_mapped(&[source], &Config::default()).unwrap();
+        crate::optimize(&mut program);
+
+        let mappings = map.resolve(&program);
+        let commands = mappings
+            .iter()
+            .map(|m| m.origin.command.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(commands, ["push constant 7", "push constant 8", "add"]);
+        assert_eq!(mappings[0].start, 0);
+        assert!(mappings
+            .windows(2)
+            .all(|w| w[0].end == w[1].start && w[0].start < w[0].end));
+        assert_eq!(mappings[2].origin.line, Some(4));
+
+        // The END loop follows the last command
+        let instructions = program.iter().filter(|asm| asm.is_instruction()).count();
+        assert_eq!(mappings[2].end as usize, instructions - 2);
+
+        assert!(map
+            .to_json(&program)
+            .contains("\"file\": \"Main.vm\", \"line\": 4, \"command\": \"add\"}\n]"));
+    }
+}
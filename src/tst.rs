@@ -0,0 +1,508 @@
+//! Runner for nand2tetris CPUEmulator test scripts (`.tst`) and their `.cmp` files
+//!
+//! Supports `load`, `output-file`, `compare-to`, `output-list`, `set`,
+//! `output`, `ticktock` and `repeat N { ... }`, which is what the
+//! project 7 and 8 scripts use.
+
+use crate::{
+    emulator::{Emulator, EmulatorError},
+    hack::ROM_SIZE,
+};
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum TstError {
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("could not load {name}: {message}")]
+    Load { name: String, message: String },
+    #[error(transparent)]
+    Emulator(#[from] EmulatorError),
+    #[error("comparison failure at line {line}: expected `{expected}`, got `{actual}`")]
+    Mismatch {
+        line: usize,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// A register or RAM word readable by `output-list` and writable by `set`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variable {
+    Ram(u16),
+    A,
+    D,
+    PC,
+}
+
+impl Variable {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "A" => Some(Variable::A),
+            "D" => Some(Variable::D),
+            "PC" => Some(Variable::PC),
+            _ => {
+                let addr = s.strip_prefix("RAM[")?.strip_suffix(']')?.parse().ok()?;
+                (addr < crate::emulator::RAM_SIZE as u16).then_some(Variable::Ram(addr))
+            }
+        }
+    }
+
+    fn get(self, emulator: &Emulator) -> i16 {
+        match self {
+            Variable::Ram(addr) => emulator.peek(addr),
+            Variable::A => emulator.a,
+            Variable::D => emulator.d,
+            Variable::PC => emulator.pc as i16,
+        }
+    }
+
+    fn set(self, emulator: &mut Emulator, value: i16) {
+        match self {
+            Variable::Ram(addr) => emulator.poke(addr, value),
+            Variable::A => emulator.a = value,
+            Variable::D => emulator.d = value,
+            Variable::PC => emulator.pc = value as u16,
+        }
+    }
+}
+
+/// One `output-list` column, e.g. `RAM[256]%D1.6.1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputColumn {
+    name: String,
+    variable: Variable,
+    /// One of `D`, `X`, `B`
+    radix: char,
+    pad_left: usize,
+    width: usize,
+    pad_right: usize,
+}
+
+impl OutputColumn {
+    fn parse(s: &str) -> Option<Self> {
+        let (name, format) = s.split_once('%').unwrap_or((s, "D1.6.1"));
+        let mut chars = format.chars();
+        let radix = chars.next().filter(|c| matches!(c, 'D' | 'X' | 'B'))?;
+        let mut sizes = chars.as_str().split('.').map(|n| n.parse::<usize>().ok());
+        Some(Self {
+            name: name.to_string(),
+            variable: Variable::parse(name)?,
+            radix,
+            pad_left: sizes.next()??,
+            width: sizes.next()??,
+            pad_right: sizes.next()??,
+        })
+    }
+
+    fn total_width(&self) -> usize {
+        self.pad_left + self.width + self.pad_right
+    }
+
+    /// The name, truncated or centred to the column width
+    fn header(&self) -> String {
+        let total = self.total_width();
+        let name = &self.name[..self.name.len().min(total)];
+        let left = (total - name.len()) / 2;
+        let right = total - left - name.len();
+        format!("{}{name}{}", " ".repeat(left), " ".repeat(right))
+    }
+
+    fn value(&self, emulator: &Emulator) -> String {
+        let value = self.variable.get(emulator);
+        let text = match self.radix {
+            'X' => format!("{:04X}", value as u16),
+            'B' => format!("{:016b}", value as u16),
+            _ => value.to_string(),
+        };
+        format!(
+            "{}{text:>width$}{}",
+            " ".repeat(self.pad_left),
+            " ".repeat(self.pad_right),
+            width = self.width
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Load(String),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<OutputColumn>),
+    Set(Variable, i16),
+    Output,
+    Ticktock,
+    Repeat(u64, Vec<Statement>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    /// `,` or `;`
+    End,
+    Open,
+    Close,
+}
+
+/// Split a script into tokens with their line numbers, dropping comments
+fn tokenize(script: &str) -> Vec<(usize, Token)> {
+    let mut tokens = Vec::new();
+    let mut in_block_comment = false;
+    for (idx, mut line) in script.lines().enumerate() {
+        let line_no = idx + 1;
+        while !line.is_empty() {
+            if in_block_comment {
+                match line.find("*/") {
+                    Some(end) => {
+                        line = &line[end + 2..];
+                        in_block_comment = false;
+                    }
+                    None => line = "",
+                }
+                continue;
+            }
+            line = line.trim_start();
+            if line.starts_with("//") {
+                break;
+            }
+            if let Some(rest) = line.strip_prefix("/*") {
+                in_block_comment = true;
+                line = rest;
+                continue;
+            }
+            let mut chars = line.chars();
+            let token = match chars.next() {
+                None => break,
+                Some(',' | ';') => Token::End,
+                Some('{') => Token::Open,
+                Some('}') => Token::Close,
+                Some(_) => {
+                    let end = line
+                        .find(|c: char| c.is_whitespace() || matches!(c, ',' | ';' | '{' | '}'))
+                        .unwrap_or(line.len());
+                    let word = &line[..end];
+                    line = &line[end..];
+                    tokens.push((line_no, Token::Word(word.to_string())));
+                    continue;
+                }
+            };
+            tokens.push((line_no, token));
+            line = chars.as_str();
+        }
+    }
+    tokens
+}
+
+/// Parse a number written as `123`, `-1`, `%X1F`, `%B101` or `%D12`
+fn parse_value(s: &str) -> Option<i16> {
+    let (radix, digits) = match s.strip_prefix('%') {
+        Some(rest) => match rest.split_at_checked(1)? {
+            ("X", digits) => (16, digits),
+            ("B", digits) => (2, digits),
+            ("D", digits) => (10, digits),
+            _ => return None,
+        },
+        None => (10, s),
+    };
+    if radix == 10 {
+        digits.parse::<i16>().ok()
+    } else {
+        u16::from_str_radix(digits, radix).ok().map(|v| v as i16)
+    }
+}
+
+struct Parser {
+    tokens: std::vec::IntoIter<(usize, Token)>,
+    line: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, TstError> {
+        Err(TstError::Syntax {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let (line, token) = self.tokens.next()?;
+        self.line = line;
+        Some(token)
+    }
+
+    /// Statements until end of input, or until `}` when `nested`
+    fn statements(&mut self, nested: bool) -> Result<Vec<Statement>, TstError> {
+        let mut statements = Vec::new();
+        loop {
+            let words = match self.next() {
+                None if nested => return self.error("missing `}`"),
+                None => return Ok(statements),
+                Some(Token::Close) if nested => return Ok(statements),
+                Some(Token::End) => continue,
+                Some(Token::Word(word)) => self.words(word)?,
+                Some(token) => return self.error(format!("unexpected {token:?}")),
+            };
+            let (command, args) = words.split_first().expect("at least one word");
+            let statement = match (command.as_str(), args) {
+                ("load", [file]) => Statement::Load(file.clone()),
+                ("output-file", [file]) => Statement::OutputFile(file.clone()),
+                ("compare-to", [file]) => Statement::CompareTo(file.clone()),
+                ("output-list", columns) => {
+                    let mut parsed = Vec::new();
+                    for column in columns {
+                        match OutputColumn::parse(column) {
+                            Some(column) => parsed.push(column),
+                            None => return self.error(format!("bad output column {column}")),
+                        }
+                    }
+                    Statement::OutputList(parsed)
+                }
+                ("set", [variable, value]) => {
+                    match (Variable::parse(variable), parse_value(value)) {
+                        (Some(variable), Some(value)) => Statement::Set(variable, value),
+                        _ => return self.error(format!("bad set {variable} {value}")),
+                    }
+                }
+                ("output", []) => Statement::Output,
+                ("ticktock", []) => Statement::Ticktock,
+                ("repeat", [count]) => {
+                    let Ok(count) = count.parse::<u64>() else {
+                        return self.error(format!("bad repeat count {count}"));
+                    };
+                    Statement::Repeat(count, self.statements(true)?)
+                }
+                _ => return self.error(format!("unsupported command {}", words.join(" "))),
+            };
+            statements.push(statement);
+        }
+    }
+
+    /// Words of one command, up to `,`/`;` or the `{` of a repeat
+    fn words(&mut self, first: String) -> Result<Vec<String>, TstError> {
+        let is_repeat = first == "repeat";
+        let mut words = vec![first];
+        loop {
+            match self.next() {
+                Some(Token::Word(word)) => words.push(word),
+                Some(Token::Open) if is_repeat => return Ok(words),
+                Some(Token::End) if !is_repeat => return Ok(words),
+                Some(token) => return self.error(format!("unexpected {token:?}")),
+                None => return self.error(format!("unterminated command {}", words.join(" "))),
+            }
+        }
+    }
+}
+
+/// A parsed `.tst` script
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestScript {
+    statements: Vec<Statement>,
+}
+
+/// Result of running a [`TestScript`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScriptOutput {
+    pub output_file: Option<String>,
+    pub compare_to: Option<String>,
+    /// Contents that would be written to the output file
+    pub output: String,
+}
+
+impl TestScript {
+    pub fn parse(script: &str) -> Result<Self, TstError> {
+        let mut parser = Parser {
+            tokens: tokenize(script).into_iter(),
+            line: 0,
+        };
+        Ok(Self {
+            statements: parser.statements(false)?,
+        })
+    }
+
+    /// Run the script, calling `load` with the argument of each `load` command
+    ///
+    /// Scripts that use the emulator before any `load` get the default
+    /// program, requested with `load(None)`
+    pub fn run<F>(&self, mut load: F) -> Result<ScriptOutput, TstError>
+    where
+        F: FnMut(Option<&str>) -> Result<Emulator, TstError>,
+    {
+        let mut state = RunState {
+            emulator: None,
+            columns: Vec::new(),
+            output: ScriptOutput::default(),
+        };
+        state.execute(&self.statements, &mut load)?;
+        Ok(state.output)
+    }
+}
+
+struct RunState {
+    emulator: Option<Emulator>,
+    columns: Vec<OutputColumn>,
+    output: ScriptOutput,
+}
+
+impl RunState {
+    fn emulator<F>(&mut self, load: &mut F) -> Result<&mut Emulator, TstError>
+    where
+        F: FnMut(Option<&str>) -> Result<Emulator, TstError>,
+    {
+        if self.emulator.is_none() {
+            self.emulator = Some(load(None)?);
+        }
+        Ok(self.emulator.as_mut().expect("loaded above"))
+    }
+
+    fn execute<F>(&mut self, statements: &[Statement], load: &mut F) -> Result<(), TstError>
+    where
+        F: FnMut(Option<&str>) -> Result<Emulator, TstError>,
+    {
+        for statement in statements {
+            match statement {
+                Statement::Load(name) => self.emulator = Some(load(Some(name))?),
+                Statement::OutputFile(name) => self.output.output_file = Some(name.clone()),
+                Statement::CompareTo(name) => self.output.compare_to = Some(name.clone()),
+                Statement::OutputList(columns) => {
+                    self.columns = columns.clone();
+                    let header = columns.iter().map(OutputColumn::header).collect::<Vec<_>>();
+                    self.output.output += &format!("|{}|\n", header.join("|"));
+                }
+                Statement::Set(variable, value) => {
+                    variable.set(self.emulator(load)?, *value);
+                }
+                Statement::Output => {
+                    self.emulator(load)?;
+                    let emulator = self.emulator.as_ref().expect("loaded above");
+                    let values = self
+                        .columns
+                        .iter()
+                        .map(|column| column.value(emulator))
+                        .collect::<Vec<_>>();
+                    self.output.output += &format!("|{}|\n", values.join("|"));
+                }
+                Statement::Ticktock => {
+                    // A halted program keeps spinning on its END loop, which
+                    // shows in A and PC like on the CPU emulator
+                    let emulator = self.emulator(load)?;
+                    match emulator.step() {
+                        // The CPU emulator's ROM is always 32K words, and
+                        // words past the program read as `@0`
+                        Err(EmulatorError::PcOutOfRange(pc)) if (pc as usize) < ROM_SIZE => {
+                            emulator.a = 0;
+                            emulator.pc += 1;
+                        }
+                        result => result?,
+                    }
+                }
+                Statement::Repeat(count, body) => {
+                    for _ in 0..*count {
+                        self.execute(body, load)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compare script output against a `.cmp` file line by line, ignoring
+/// whitespace as the nand2tetris CPU emulator does
+pub fn compare(output: &str, cmp: &str) -> Result<(), TstError> {
+    let mut expected = cmp.lines().map(str::trim_end);
+    let mut actual = output.lines().map(str::trim_end);
+    let squash = |line: &str| line.split_whitespace().collect::<String>();
+    let mut line = 0;
+    loop {
+        line += 1;
+        match (expected.next(), actual.next()) {
+            (None, None) => return Ok(()),
+            (Some(e), Some(a)) if squash(e) == squash(a) => {}
+            (e, a) => {
+                return Err(TstError::Mismatch {
+                    line,
+                    expected: e.unwrap_or_default().to_string(),
+                    actual: a.unwrap_or_default().to_string(),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse, translate, Config, Source};
+
+    const SCRIPT: &str = "
+        load Add.asm, /* loaded by the test */
+        output-list RAM[0]%D2.6.2 RAM[256]%D1.6.1 D%B0.16.0;
+        set RAM[0] 256,
+        repeat 100 {
+            ticktock;
+        }
+        output;
+    ";
+
+    #[test]
+    fn test_run_script() {
+        let script = TestScript::parse(SCRIPT).unwrap();
+        let output = script
+            .run(|name| {
+                assert_eq!(name, Some("Add.asm"));
+                let commands = parse("push constant 7\npush constant 8\nadd").unwrap();
+                let program =
+                    translate(&[Source::new("Add", commands)], &Config::default()).unwrap();
+                Ok(Emulator::from_assembly(&program).unwrap())
+            })
+            .unwrap();
+        assert_eq!(
+            output.output,
+            "|  RAM[0]  |RAM[256]|       D        |\n|     257  |     15 |0000000000001111|\n"
+        );
+    }
+
+    #[test]
+    fn test_run_past_program() {
+        let script =
+            TestScript::parse("output-list A%D1.3.1; repeat 3 { ticktock; } output;").unwrap();
+        let output = script.run(|_| Ok(Emulator::new(vec![7]))).unwrap();
+        // `@7` then empty ROM, which reads as `@0`
+        assert_eq!(output.output, "|  A  |\n|   0 |\n");
+    }
+
+    #[test]
+    fn test_ticktock_after_halt() {
+        let script =
+            TestScript::parse("output-list PC%D1.3.1; repeat 3 { ticktock; } output;").unwrap();
+        // (END) @END 0;JMP
+        let output = script.run(|_| Ok(Emulator::new(vec![0, 0xEA87]))).unwrap();
+        assert_eq!(output.output, "| PC  |\n|   1 |\n");
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert!(matches!(
+            TestScript::parse("load Foo.asm,\nrepeat 3 {\nticktock;"),
+            Err(TstError::Syntax { line: 3, .. })
+        ));
+        assert!(matches!(
+            TestScript::parse("set RAM[0] x;"),
+            Err(TstError::Syntax { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_compare() {
+        assert!(compare("| 1 |\n", "| 1 |\r\n").is_ok());
+        assert!(compare("|   1 |\n", "  | 1|").is_ok());
+        assert_eq!(
+            compare("| 1 |\n| 2 |\n", "| 1 |\n| 3 |"),
+            Err(TstError::Mismatch {
+                line: 2,
+                expected: "| 3 |".to_string(),
+                actual: "| 2 |".to_string()
+            })
+        );
+    }
+}
@@ -6,6 +6,7 @@ pub fn remove_whitespace_comments(mut s: &str) -> Option<&str> {
     if let Some(comment_idx) = s.find("//") {
         s = &s[..comment_idx];
     }
+    s = s.trim();
 
     if s.is_empty() {
         None